@@ -1,7 +1,6 @@
 #!/usr/bin/env python3
 
 import platform
-import itertools
 from pathlib import Path
 import subprocess
 import argparse
@@ -29,21 +28,10 @@ else:
 if not exe.exists():
     raise Exception(f'Build failed?, {exe} not found')
 
-for i in itertools.count(getattr(args, 'from')):
-    src = output_dir / f'{i:04d}.npz'
-    if not src.exists():
-        print(f'File {src} not found, stopping')
-        break
-    out = output_dir / f'{i:04d}_out.npz'
-    if not out.exists():
-        try:
-            if i == 0:
-                run([exe, src, '-o', out])
-            else:
-                ref = output_dir / f'{i-1:04d}_out.npz'
-                run([exe, src, ref, '-o', out])
-        except KeyboardInterrupt:
-            if out.exists():
-                out.unlink()
-            print(f"Stopped at frame #{i:04}")
-            break
+# frames are walked, skipped and chained by `tetris batch`
+try:
+    run([exe, 'batch', output_dir, '--from', str(getattr(args, 'from'))])
+except KeyboardInterrupt:
+    for tmp in output_dir.glob('*_out.npz.tmp'):
+        tmp.unlink()
+    print("Stopped")
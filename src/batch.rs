@@ -0,0 +1,303 @@
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use ndarray::Array2;
+
+use crate::{identity, img, npz, solve};
+
+/// Input and output file of frame #`index` in `dir`
+pub fn frame_path(dir: &Path, index: usize) -> (PathBuf, PathBuf) {
+    (
+        dir.join(format!("{:04}.npz", index)),
+        dir.join(format!("{:04}_out.npz", index)),
+    )
+}
+
+/// Solve one input file against an optional reference output file
+pub fn solve_file(
+    file: &Path,
+    ref_file: Option<&Path>,
+    output: &Path,
+    opts: &solve::Options,
+    arrays: &[npz::OutputArray],
+) -> Result<()> {
+    let input = npz::read_input(file)?;
+    let (ref_map, unfilled, prev_id) = read_ref(ref_file, &input.map)?;
+    let composite = solve::frame(&input, ref_map, unfilled.as_ref(), opts);
+    write(&input, &composite, prev_id.as_ref(), output, arrays)
+}
+
+/// Reference layout, map cells it left uncovered and its piece ids
+type Reference = (
+    Option<Array2<u8>>,
+    Option<Array2<bool>>,
+    Option<Array2<u32>>,
+);
+
+fn read_ref(ref_file: Option<&Path>, map: &Array2<bool>) -> Result<Reference> {
+    Ok(if let Some(ref_file) = ref_file {
+        let r = npz::read_ref(ref_file, Some(map.shape()))?;
+        (Some(r.data), r.unfilled, r.id)
+    } else {
+        (None, None, None)
+    })
+}
+
+/// Write output with piece ids carried on from `prev_id`. Output is written to a temporary
+/// file first, so an interrupted run never leaves a partial output.
+fn write(
+    input: &solve::Input,
+    composite: &Array2<u8>,
+    prev_id: Option<&Array2<u32>>,
+    output: &Path,
+    arrays: &[npz::OutputArray],
+) -> Result<()> {
+    log!("Final");
+    for row in img::dump(&input.map, composite) {
+        log!("|{}|", row);
+    }
+
+    let id = identity::assign(prev_id, composite);
+    let mut tmp = output.as_os_str().to_owned();
+    tmp.push(".tmp");
+    npz::write_output(&tmp, &input.map, composite, &id, arrays)?;
+    std::fs::rename(&tmp, output).with_context(|| "Cannot write output file")?;
+    Ok(())
+}
+
+/// Solve numbered frames `from..=to` in `dir`, each against the previous frame's output.
+/// Frames that already have an output are skipped, so an interrupted run can be resumed.
+/// Outputs always have `piece` or `label`, for the next frame to read.
+///
+/// With `pipeline`, the next frame is solved at the same time against a provisional layout
+/// of this frame: its own speculative solve, or the reference pieces that still fit it.
+/// Then only its parts whose final reference differs are solved again.
+pub fn run(
+    dir: &Path,
+    from: usize,
+    to: Option<usize>,
+    pipeline: bool,
+    opts: &solve::Options,
+    arrays: &[npz::OutputArray],
+) -> Result<()> {
+    remove_partial(dir)?;
+    let mut arrays = arrays.to_vec();
+    if !arrays
+        .iter()
+        .any(|a| matches!(a, npz::OutputArray::Piece | npz::OutputArray::Label))
+    {
+        log!("batch: writing piece too, for the next frame");
+        arrays.push(npz::OutputArray::Piece);
+    }
+    let arrays = &arrays[..];
+    let last = to.unwrap_or(usize::MAX);
+    // layout and parts of the next frame, solved against a provisional reference
+    let mut speculative: Option<(usize, Array2<u8>, solve::Cache)> = None;
+    for index in from..=last {
+        let (input, output) = frame_path(dir, index);
+        if !input.exists() {
+            log!("File {} not found, stopping", input.display());
+            break;
+        }
+        if output.exists() {
+            log!("frame #{:04}: done, skipping", index);
+            continue;
+        }
+        let ref_file = index
+            .checked_sub(1)
+            .map(|i| frame_path(dir, i).1)
+            .filter(|p| p.exists());
+        log!("frame #{:04}", index);
+        if !pipeline {
+            solve_file(&input, ref_file.as_deref(), &output, opts, arrays)
+                .with_context(|| format!("frame #{:04}", index))?;
+            continue;
+        }
+
+        let frame = npz::read_input(&input).with_context(|| format!("frame #{:04}", index))?;
+        let (ref_map, unfilled, prev_id) = read_ref(ref_file.as_deref(), &frame.map)?;
+        let (provisional, mut cache) = match speculative.take() {
+            Some((i, data, cache)) if i == index => (Some(data), cache),
+            _ => (
+                ref_map
+                    .as_ref()
+                    .map(|m| img::transfer(frame.map.view(), m.view())),
+                Default::default(),
+            ),
+        };
+        let (next_input, next_output) = frame_path(dir, index + 1);
+        let next = if index < last && next_input.exists() && !next_output.exists() {
+            Some(npz::read_input(&next_input).with_context(|| format!("frame #{:04}", index + 1))?)
+        } else {
+            None
+        };
+        let (composite, next_cache) = std::thread::scope(|scope| {
+            let handle = next.as_ref().map(|next| {
+                let map = &frame.map;
+                scope.spawn(move || {
+                    log!("frame #{:04}: speculative", index + 1);
+                    speculate(next, provisional, map, opts)
+                })
+            });
+            let composite =
+                solve::frame_cached(&frame, ref_map, unfilled.as_ref(), opts, &mut cache);
+            (composite, handle.map(|h| h.join().unwrap()))
+        });
+        write(&frame, &composite, prev_id.as_ref(), &output, arrays)
+            .with_context(|| format!("frame #{:04}", index))?;
+        speculative = next_cache.map(|(data, cache)| (index + 1, data, cache));
+    }
+    Ok(())
+}
+
+/// Remove outputs an interrupted run left half written
+fn remove_partial(dir: &Path) -> Result<()> {
+    let entries = std::fs::read_dir(dir).with_context(|| "Cannot read working directory")?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with("_out.npz.tmp") {
+            log!("removing partial output {}", path.display());
+            std::fs::remove_file(&path).with_context(|| "Cannot remove partial output")?;
+        }
+    }
+    Ok(())
+}
+
+/// Solve `next` frame against `provisional`, the expected layout over this frame's `map`
+fn speculate(
+    next: &solve::Input,
+    provisional: Option<Array2<u8>>,
+    map: &Array2<bool>,
+    opts: &solve::Options,
+) -> (Array2<u8>, solve::Cache) {
+    let unfilled = provisional
+        .as_ref()
+        .map(|m| map & &img::lay(m).mapv(|v| !v));
+    let mut cache = solve::Cache::default();
+    let data = solve::frame_cached(next, provisional, unfilled.as_ref(), opts, &mut cache);
+    (data, cache)
+}
+
+/// Solve frames of a stacked sequence in order, each against the previous one,
+/// the first one against `ref_file` if given
+pub fn run_stack(
+    inputs: &[solve::Input],
+    ref_file: Option<&Path>,
+    output: &Path,
+    opts: &solve::Options,
+    arrays: &[npz::OutputArray],
+) -> Result<()> {
+    let (mut ref_map, mut unfilled, mut prev_id) = match inputs.first() {
+        Some(input) => read_ref(ref_file, &input.map)?,
+        None => (None, None, None),
+    };
+    let mut solved = vec![];
+    for (index, input) in inputs.iter().enumerate() {
+        log!("frame #{:04}", index);
+        let start = Instant::now();
+        let data = solve::frame(input, ref_map.take(), unfilled.as_ref(), opts);
+        let id = identity::assign(prev_id.as_ref(), &data);
+        log!("Final");
+        for row in img::dump(&input.map, &data) {
+            log!("|{}|", row);
+        }
+        ref_map = Some(data.clone());
+        unfilled = Some(&input.map & &img::lay(&data).mapv(|v| !v));
+        prev_id = Some(id.clone());
+        solved.push(npz::Solved {
+            data,
+            id,
+            seconds: start.elapsed().as_secs_f64(),
+        });
+    }
+    let mut tmp = output.as_os_str().to_owned();
+    tmp.push(".tmp");
+    npz::write_stack(&tmp, inputs, &solved, arrays)?;
+    std::fs::rename(&tmp, output).with_context(|| "Cannot write output file")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    /// Fresh scratch directory with numbered inputs of `maps`
+    fn scratch(name: &str, maps: &[Array2<bool>]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tetris_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (i, map) in maps.iter().enumerate() {
+            let gray = map.mapv(|v| v as u8 * 255);
+            npz::write_input(frame_path(&dir, i).0, map, &gray).unwrap();
+        }
+        dir
+    }
+
+    fn layout(dir: &Path, index: usize) -> (Array2<u8>, Option<Array2<u32>>) {
+        let r = npz::read_ref(frame_path(dir, index).1, None).unwrap();
+        (r.data, r.id)
+    }
+
+    #[test]
+    fn test_resume() {
+        let opts = solve::Options {
+            motion: solve::Motion::Off,
+            ..Default::default()
+        };
+        let map = Array2::from_elem((4, 4), true);
+        let dir = scratch("resume", &[map.clone(), map.clone(), map.clone()]);
+        let partial = dir.join("0001_out.npz.tmp");
+        std::fs::write(&partial, b"").unwrap();
+        let arrays = [npz::OutputArray::Type, npz::OutputArray::Id];
+        run(&dir, 0, Some(0), false, &opts, &arrays).unwrap();
+        assert!(!partial.exists());
+        assert!(!frame_path(&dir, 1).1.exists());
+
+        // done frames are kept as they are, and the run stops at the first missing input
+        let (_, id) = layout(&dir, 0);
+        let marker = Array2::zeros((4, 4));
+        let output = frame_path(&dir, 0).1;
+        npz::write_output(output, &map, &marker, &id.unwrap(), &npz::OutputArray::ALL).unwrap();
+        run(&dir, 0, None, false, &opts, &arrays).unwrap();
+        assert_eq!(layout(&dir, 0).0, marker);
+        for i in 1..3 {
+            assert!(img::lay(&layout(&dir, i).0).iter().all(|v| *v));
+        }
+        assert!(!frame_path(&dir, 3).1.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let opts = solve::Options {
+            motion: solve::Motion::Off,
+            ..Default::default()
+        };
+        let map = Array2::from_elem((4, 8), true);
+        let mut moved = map.clone();
+        moved.slice_mut(s![.., 7]).fill(false);
+        moved.slice_mut(s![.., 0]).fill(false);
+        let maps = [map.clone(), map.clone(), moved];
+        let plain = scratch("plain", &maps);
+        let piped = scratch("piped", &maps);
+        run(&plain, 0, None, false, &opts, &npz::OutputArray::ALL).unwrap();
+        run(&piped, 0, None, true, &opts, &npz::OutputArray::ALL).unwrap();
+        for i in 0..maps.len() {
+            assert_eq!(layout(&plain, i), layout(&piped, i));
+        }
+
+        // next frame speculated against the layout this frame ends up with is not solved again
+        let (data, _) = layout(&piped, 0);
+        let next = npz::read_input(frame_path(&piped, 1).0).unwrap();
+        let (speculative, mut cache) = speculate(&next, Some(data.clone()), &map, &opts);
+        let unfilled = &map & &img::lay(&data).mapv(|v| !v);
+        let out = solve::frame_cached(&next, Some(data), Some(&unfilled), &opts, &mut cache);
+        assert!(cache.reused > 0);
+        assert_eq!(out, speculative);
+        for dir in [plain, piped] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use crate::img;
+use crate::piece::{self, TETROMINO};
+use ndarray::Array2;
+use std::cmp::min;
+use std::fmt;
+
+/// Reason why a map provably cannot be tiled
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Infeasible {
+    /// Number of cells is not divisible by 4
+    Size(usize),
+    /// Checkerboard colors are too unbalanced, only T piece can cover 3 cells of one color
+    Coloring { black: usize, white: usize },
+    /// No piece placement can cover this cell
+    Uncoverable { y: usize, x: usize },
+    /// Removing this cell splits the map into parts that one piece cannot join
+    Articulation { y: usize, x: usize },
+    /// Cell is left uncoverable after placing the pieces forced around it
+    DeadEnd { y: usize, x: usize },
+    /// Forced pieces cut off an area which its size cannot divide by 4
+    Fragment { y: usize, x: usize, size: usize },
+}
+
+impl fmt::Display for Infeasible {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Size(size) => write!(f, "size {} is not divisible by 4", size),
+            Self::Coloring { black, white } => {
+                write!(
+                    f,
+                    "checkerboard imbalance ({} black, {} white)",
+                    black, white
+                )
+            }
+            Self::Uncoverable { y, x } => write!(f, "cell ({}, {}) cannot be covered", y, x),
+            Self::Articulation { y, x } => {
+                write!(f, "cell ({}, {}) cuts map into untileable parts", y, x)
+            }
+            Self::DeadEnd { y, x } => write!(f, "dead end at ({}, {})", y, x),
+            Self::Fragment { y, x, size } => {
+                write!(f, "forced pieces cut off {} cells at ({}, {})", size, y, x)
+            }
+        }
+    }
+}
+
+/// Run quick necessary conditions for `map` to be fully tileable.
+/// `Ok` does not mean the map is tileable.
+pub fn check(map: &Array2<bool>) -> Result<(), Infeasible> {
+    let size = map.iter().filter(|v| **v).count();
+    if size % 4 != 0 {
+        return Err(Infeasible::Size(size));
+    }
+    coloring(map)?;
+    for ((y, x), &v) in map.indexed_iter() {
+        if v && covering(map, (y, x)).next().is_none() {
+            return Err(Infeasible::Uncoverable { y, x });
+        }
+    }
+    articulation(map)?;
+    dead_end(map)?;
+    Ok(())
+}
+
+/// Placements that cover `(y, x)` using only cells in `free`
+fn covering(
+    free: &Array2<bool>,
+    (y, x): (usize, usize),
+) -> impl Iterator<Item = [(usize, usize); 4]> + '_ {
+    let &[h, w] = free.shape() else {
+        unreachable!()
+    };
+    (1..TETROMINO.len()).flat_map(move |v| {
+        TETROMINO[v].iter().filter_map(move |&(dy, dx)| {
+            let (dy, dx) = (dy as usize, dx as usize);
+            if y < dy || x < dx {
+                return None;
+            }
+            let cells = piece::cells(v as u8, (y - dy, x - dx), (h, w))?;
+            cells.iter().all(|c| free[*c]).then_some(cells)
+        })
+    })
+}
+
+fn coloring(map: &Array2<bool>) -> Result<(), Infeasible> {
+    let (mut black, mut white) = (0usize, 0usize);
+    for ((y, x), &v) in map.indexed_iter() {
+        if v {
+            if (y + x) % 2 == 0 {
+                black += 1;
+            } else {
+                white += 1;
+            }
+        }
+    }
+    // every piece covers 2 + 2 cells except T which covers 3 + 1
+    let pieces = (black + white) / 4;
+    if black.abs_diff(white) > 2 * pieces {
+        return Err(Infeasible::Coloring { black, white });
+    }
+    Ok(())
+}
+
+/// The piece covering a cut cell takes 3 more cells from the parts around it.
+/// Each part has to give away its size mod 4, and they must add up to 3.
+fn articulation(map: &Array2<bool>) -> Result<(), Infeasible> {
+    let &[h, w] = map.shape() else { unreachable!() };
+    const NONE: usize = usize::MAX;
+    let n = h * w;
+    let mut disc = vec![NONE; n];
+    let mut low = vec![0; n];
+    let mut size = vec![0; n];
+    let mut parent = vec![NONE; n];
+    let mut cut: Vec<Vec<usize>> = vec![vec![]; n];
+    let mut time = 0;
+
+    let neighbour = |i: usize, k: usize| -> Option<usize> {
+        let (y, x) = (i / w, i % w);
+        let (y, x) = match k {
+            0 if y > 0 => (y - 1, x),
+            1 if y < h - 1 => (y + 1, x),
+            2 if x > 0 => (y, x - 1),
+            3 if x < w - 1 => (y, x + 1),
+            _ => return None,
+        };
+        map[(y, x)].then_some(y * w + x)
+    };
+
+    for root in 0..n {
+        if !map[(root / w, root % w)] || disc[root] != NONE {
+            continue;
+        }
+        let mut visited = vec![root];
+        disc[root] = time;
+        low[root] = time;
+        size[root] = 1;
+        time += 1;
+        let mut stack = vec![(root, 0)];
+        while let Some((v, k)) = stack.last_mut() {
+            let v = *v;
+            if *k < 4 {
+                let u = neighbour(v, *k);
+                *k += 1;
+                let Some(u) = u else { continue };
+                if disc[u] == NONE {
+                    parent[u] = v;
+                    disc[u] = time;
+                    low[u] = time;
+                    size[u] = 1;
+                    time += 1;
+                    visited.push(u);
+                    stack.push((u, 0));
+                } else if u != parent[v] {
+                    low[v] = min(low[v], disc[u]);
+                }
+            } else {
+                stack.pop();
+                if let Some(&(p, _)) = stack.last() {
+                    low[p] = min(low[p], low[v]);
+                    size[p] += size[v];
+                    if low[v] >= disc[p] {
+                        cut[p].push(size[v]);
+                    }
+                }
+            }
+        }
+
+        let total = size[root];
+        for &v in &visited {
+            let rest = total - 1 - cut[v].iter().sum::<usize>();
+            let parts = cut[v].len() + (rest > 0) as usize;
+            if parts < 2 {
+                continue;
+            }
+            let need: usize = cut[v].iter().map(|s| s % 4).sum::<usize>() + rest % 4;
+            if need != 3 {
+                return Err(Infeasible::Articulation { y: v / w, x: v % w });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Place every piece that is the only way to cover some cell, until nothing is forced
+fn dead_end(map: &Array2<bool>) -> Result<(), Infeasible> {
+    let &[h, w] = map.shape() else { unreachable!() };
+    let mut free = map.clone();
+    let mut progress = true;
+    while progress {
+        progress = false;
+        for y in 0..h {
+            for x in 0..w {
+                if !free[(y, x)] {
+                    continue;
+                }
+                let found: Vec<_> = covering(&free, (y, x)).take(2).collect();
+                match found[..] {
+                    [] => return Err(Infeasible::DeadEnd { y, x }),
+                    [cells] => {
+                        for c in cells {
+                            free[c] = false;
+                        }
+                        progress = true;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    for seg in img::segment(&free) {
+        if seg.map_size % 4 != 0 {
+            let ((y, x), _) = seg.map.indexed_iter().find(|(_, v)| **v).unwrap();
+            return Err(Infeasible::Fragment {
+                y: seg.y + y,
+                x: seg.x + x,
+                size: seg.map_size,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    #[test]
+    fn test_feasible() {
+        let map = array![
+            [1, 0, 0, 1], //
+            [1, 1, 1, 1],
+            [1, 0, 0, 1],
+        ]
+        .mapv(|x| x != 0);
+        assert_eq!(check(&map), Ok(()));
+    }
+    #[test]
+    fn test_size() {
+        let map = array![
+            [0, 1, 1], //
+            [1, 1, 1],
+        ]
+        .mapv(|x| x != 0);
+        assert_eq!(check(&map), Err(Infeasible::Size(5)));
+    }
+    #[test]
+    fn test_articulation() {
+        let map = array![
+            [0, 0, 0, 1, 0], //
+            [0, 0, 0, 1, 0],
+            [0, 0, 0, 1, 0],
+            [1, 1, 1, 1, 1],
+        ]
+        .mapv(|x| x != 0);
+        assert_eq!(check(&map), Err(Infeasible::Articulation { y: 3, x: 3 }));
+    }
+}
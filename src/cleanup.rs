@@ -0,0 +1,234 @@
+use std::fmt;
+
+use ndarray::Array2;
+
+/// One map cell changed by `clean`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Change {
+    pub y: usize,
+    pub x: usize,
+    /// Cell was filled, otherwise cleared
+    pub filled: bool,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.filled { '+' } else { '-' };
+        write!(f, "{}({}, {})", sign, self.y, self.x)
+    }
+}
+
+/// In-bounds 4-neighbours of a cell
+fn neighbours(
+    (y, x): (usize, usize),
+    (h, w): (usize, usize),
+) -> impl Iterator<Item = (usize, usize)> {
+    [(-1, 0), (1, 0), (0, -1), (0, 1)]
+        .into_iter()
+        .filter_map(move |(dy, dx)| {
+            let (y, x) = (y.checked_add_signed(dy)?, x.checked_add_signed(dx)?);
+            (y < h && x < w).then_some((y, x))
+        })
+}
+
+/// Component id (from 1) of each filled cell, and size of each component
+fn components(map: &Array2<bool>) -> (Array2<usize>, Vec<usize>) {
+    let mut id = Array2::zeros(map.raw_dim());
+    let mut size = vec![0];
+    for (start, &v) in map.indexed_iter() {
+        if !v || id[start] != 0 {
+            continue;
+        }
+        let n = size.len();
+        id[start] = n;
+        let mut stack = vec![start];
+        let mut count = 0;
+        while let Some(c) = stack.pop() {
+            count += 1;
+            for nb in neighbours(c, map.dim()) {
+                if map[nb] && id[nb] == 0 {
+                    id[nb] = n;
+                    stack.push(nb);
+                }
+            }
+        }
+        size.push(count);
+    }
+    (id, size)
+}
+
+struct Cleaner<'a> {
+    map: Array2<bool>,
+    allowed: &'a Array2<bool>,
+    limit: usize,
+    changes: Vec<Change>,
+}
+
+impl Cleaner<'_> {
+    fn filled_around(&self, pos: (usize, usize)) -> usize {
+        neighbours(pos, self.map.dim())
+            .filter(|nb| self.map[*nb])
+            .count()
+    }
+
+    fn changed(&self, (y, x): (usize, usize)) -> bool {
+        self.changes.iter().any(|c| (c.y, c.x) == (y, x))
+    }
+
+    /// Change a cell once, within the limit and only to fill allowed cells
+    fn set(&mut self, (y, x): (usize, usize), filled: bool) -> bool {
+        if self.changes.len() >= self.limit
+            || self.changed((y, x))
+            || (filled && !self.allowed[(y, x)])
+        {
+            return false;
+        }
+        self.map[(y, x)] = filled;
+        self.changes.push(Change { y, x, filled });
+        true
+    }
+
+    /// Join islands of less than 4 cells to the largest component one empty cell away
+    fn bridge(&mut self) {
+        loop {
+            let (id, size) = components(&self.map);
+            let dim = self.map.dim();
+            // first island that can be joined: (bridge, size of the other component)
+            let mut best: Option<((usize, usize), usize)> = None;
+            for i in (1..size.len()).filter(|i| size[*i] < 4) {
+                for (pos, _) in id.indexed_iter().filter(|(_, v)| **v == i) {
+                    for b in neighbours(pos, dim) {
+                        if self.map[b] || !self.allowed[b] || self.changed(b) {
+                            continue;
+                        }
+                        for nb in neighbours(b, dim) {
+                            let j = id[nb];
+                            if j != 0 && j != i && best.is_none_or(|(_, s)| size[j] > s) {
+                                best = Some((b, size[j]));
+                            }
+                        }
+                    }
+                }
+                if best.is_some() {
+                    break;
+                }
+            }
+            let Some((b, _)) = best else {
+                return;
+            };
+            if !self.set(b, true) {
+                return;
+            }
+        }
+    }
+
+    /// Clear single cells sticking out of a wider area
+    fn spurs(&mut self) {
+        let dim = self.map.dim();
+        for y in 0..dim.0 {
+            for x in 0..dim.1 {
+                if !self.map[(y, x)] || self.filled_around((y, x)) != 1 {
+                    continue;
+                }
+                let base = neighbours((y, x), dim).find(|nb| self.map[*nb]).unwrap();
+                if !self.changed(base) && self.filled_around(base) >= 3 {
+                    self.set((y, x), false);
+                }
+            }
+        }
+    }
+
+    /// Fill pinholes: empty cells enclosed on all 4 sides. Unlike a morphological closing,
+    /// wider gaps and notches are left alone
+    fn fill_pinholes(&mut self) {
+        let dim = self.map.dim();
+        for y in 1..dim.0.saturating_sub(1) {
+            for x in 1..dim.1.saturating_sub(1) {
+                if !self.map[(y, x)] && self.filled_around((y, x)) == 4 {
+                    self.set((y, x), true);
+                }
+            }
+        }
+    }
+
+    /// Clear filled cells with no filled neighbour. Unlike a morphological opening, thin
+    /// lines that pieces can still cover are kept
+    fn clear_isolated(&mut self) {
+        let dim = self.map.dim();
+        for y in 0..dim.0 {
+            for x in 0..dim.1 {
+                if self.map[(y, x)] && self.filled_around((y, x)) == 0 {
+                    self.set((y, x), false);
+                }
+            }
+        }
+    }
+}
+
+/// Clean up features of `map` that cannot be tiled, changing at most `limit` cells:
+/// tiny islands are bridged to a neighbour, then spurs are cleared, one cell holes are
+/// filled and isolated cells cleared. Only `allowed` cells are filled.
+pub fn clean(
+    map: &Array2<bool>,
+    allowed: &Array2<bool>,
+    limit: usize,
+) -> (Array2<bool>, Vec<Change>) {
+    let mut cleaner = Cleaner {
+        map: map.clone(),
+        allowed,
+        limit,
+        changes: vec![],
+    };
+    cleaner.bridge();
+    cleaner.spurs();
+    cleaner.fill_pinholes();
+    cleaner.clear_isolated();
+    (cleaner.map, cleaner.changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    #[test]
+    fn test_clean() {
+        let map = array![
+            [0, 0, 0, 0, 0, 0, 0, 1], //
+            [1, 0, 1, 1, 1, 0, 0, 0],
+            [0, 0, 1, 0, 1, 1, 0, 0],
+            [0, 0, 1, 1, 1, 0, 0, 0],
+        ]
+        .mapv(|v| v != 0);
+        let allowed = Array2::from_elem(map.raw_dim(), true);
+        let (out, changes) = clean(&map, &allowed, 10);
+        let change = |y, x, filled| Change { y, x, filled };
+        assert_eq!(
+            changes,
+            vec![
+                // island at (1, 0) joins the ring
+                change(1, 1, true),
+                // spur off the ring
+                change(2, 5, false),
+                // hole in the ring
+                change(2, 3, true),
+                // corner cell has nothing to join
+                change(0, 7, false),
+            ]
+        );
+        for c in &changes {
+            assert_eq!(out[(c.y, c.x)], c.filled);
+        }
+        assert_eq!(changes[0].to_string(), "+(1, 1)");
+
+        // limit stops after the bridge, and empty cells are never filled
+        let (_, changes) = clean(&map, &allowed, 1);
+        assert_eq!(changes.len(), 1);
+        let mut allowed = allowed;
+        allowed[(1, 1)] = false;
+        allowed[(2, 3)] = false;
+        let (out, changes) = clean(&map, &allowed, 10);
+        assert!(changes.iter().all(|c| !c.filled));
+        assert!(!out[(1, 0)]);
+    }
+}
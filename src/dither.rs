@@ -0,0 +1,244 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use ndarray::prelude::*;
+
+use crate::{ga, img, piece, similarity};
+
+/// Generations to refine the greedy layout for at most
+const GENERATIONS: usize = 500;
+
+/// Greedy passes over the frame at most
+const PASSES: usize = 8;
+
+/// Covered cells and their 3x3 box mean, updated as pieces come and go
+struct Density<'a> {
+    target: &'a Array2<f64>,
+    cover: Array2<bool>,
+    blur: Array2<f64>,
+}
+
+impl Density<'_> {
+    /// Change of squared error from covering (or uncovering) `cells`
+    fn delta(&self, cells: &[(usize, usize)], add: bool) -> f64 {
+        let &[h, w] = self.cover.shape() else {
+            unreachable!()
+        };
+        let sign = if add { 1.0 } else { -1.0 };
+        let (y0, x0) = cells
+            .iter()
+            .fold((h, w), |(y, x), c| (y.min(c.0), x.min(c.1)));
+        let (y1, x1) = cells
+            .iter()
+            .fold((0, 0), |(y, x), c| (y.max(c.0), x.max(c.1)));
+        let mut delta = 0.0;
+        for y in y0.saturating_sub(1)..(y1 + 2).min(h) {
+            for x in x0.saturating_sub(1)..(x1 + 2).min(w) {
+                let area =
+                    ((y + 2).min(h) - y.saturating_sub(1)) * ((x + 2).min(w) - x.saturating_sub(1));
+                let n = cells
+                    .iter()
+                    .filter(|c| c.0.abs_diff(y) <= 1 && c.1.abs_diff(x) <= 1)
+                    .count();
+                if n == 0 {
+                    continue;
+                }
+                let before = self.blur[(y, x)] - self.target[(y, x)];
+                let after = before + sign * n as f64 / area as f64;
+                delta += after * after - before * before;
+            }
+        }
+        delta
+    }
+
+    /// Cover (or uncover) `cells`, updating the box mean around them only
+    fn set(&mut self, cells: &[(usize, usize)], add: bool) {
+        let &[h, w] = self.cover.shape() else {
+            unreachable!()
+        };
+        let sign = if add { 1.0 } else { -1.0 };
+        for &(cy, cx) in cells {
+            if self.cover[(cy, cx)] == add {
+                continue;
+            }
+            self.cover[(cy, cx)] = add;
+            for y in cy.saturating_sub(1)..(cy + 2).min(h) {
+                for x in cx.saturating_sub(1)..(cx + 2).min(w) {
+                    let area = ((y + 2).min(h) - y.saturating_sub(1))
+                        * ((x + 2).min(w) - x.saturating_sub(1));
+                    self.blur[(y, x)] += sign / area as f64;
+                }
+            }
+        }
+    }
+}
+
+/// Error change, piece type and cells of a piece to add
+type Placement = (f64, u8, [(usize, usize); 4]);
+
+/// Add or remove single pieces while that brings density closer to `target`, like error
+/// diffusion with whole pieces. `fixed` cells count as covered.
+fn greedy(
+    map: &Array2<bool>,
+    target: &Array2<f64>,
+    mut data: Array2<u8>,
+    fixed: &Array2<bool>,
+) -> Array2<u8> {
+    let &[h, w] = map.shape() else { unreachable!() };
+    let cover = &img::lay(&data) | fixed;
+    let mut density = Density {
+        target,
+        blur: img::box_blur(&cover.mapv(|v| v as u8 as f64)),
+        cover,
+    };
+    for _ in 0..PASSES {
+        let mut changed = false;
+        for y in 0..h {
+            for x in 0..w {
+                let v = data[(y, x)];
+                if v != 0 {
+                    let cells = piece::cells(v, (y, x), (h, w)).unwrap();
+                    if density.delta(&cells, false) < 0.0 {
+                        density.set(&cells, false);
+                        data[(y, x)] = 0;
+                        changed = true;
+                    }
+                    continue;
+                }
+                let mut best: Option<Placement> = None;
+                for v in 1..piece::TETROMINO.len() as u8 {
+                    let Some(cells) = piece::cells(v, (y, x), (h, w)) else {
+                        continue;
+                    };
+                    if cells.iter().any(|c| !map[*c] || density.cover[*c]) {
+                        continue;
+                    }
+                    let delta = density.delta(&cells, true);
+                    if delta < 0.0 && best.is_none_or(|(d, _, _)| delta < d) {
+                        best = Some((delta, v, cells));
+                    }
+                }
+                if let Some((_, v, cells)) = best {
+                    density.set(&cells, true);
+                    data[(y, x)] = v;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    data
+}
+
+/// Place pieces on `map` so that their density follows `target` (0 to 1), leaving any cell
+/// uncovered if that fits better. Starts from `ref_map`, which should fit `map`.
+/// `locked` pieces are kept and left out of the result.
+pub fn dither(
+    map: &Array2<bool>,
+    target: &Array2<f64>,
+    ref_map: Option<Array2<u8>>,
+    locked: Option<&Array2<u8>>,
+    stability: ga::Stability,
+) -> Array2<u8> {
+    let start = Instant::now();
+    let target = img::box_blur(target);
+    let pinned = match locked {
+        Some(locked) => img::lay(locked),
+        None => Array2::from_elem(map.raw_dim(), false),
+    };
+    let start_data = ref_map
+        .clone()
+        .unwrap_or_else(|| Array2::zeros(map.raw_dim()));
+    let seed = greedy(map, &target, start_data, &pinned);
+
+    let mut ga = ga::GA::new(
+        ga::Config {
+            map: map | &pinned,
+            ref_map: ref_map
+                .as_ref()
+                .map(|m| similarity::Reference::new(m.view())),
+            locked: locked.cloned(),
+            target: Some(target),
+            stability,
+            score: ga::score_dither,
+            ..Default::default()
+        },
+        0,
+        None,
+    );
+    ga.add_candidate(seed);
+    let mut last_score: VecDeque<_> = [-1, -2, -3].into();
+    while ga.generation < GENERATIONS {
+        ga.step();
+        if ga.generation.is_multiple_of(100) {
+            let score = ga.candidate[0].score;
+            if last_score.iter().all(|v| *v == score) {
+                break;
+            }
+            last_score.pop_front();
+            last_score.push_back(score);
+        }
+    }
+    let best = &ga.candidate[0];
+    let mut data = (*best.data).clone();
+    if let Some(locked) = locked {
+        data.zip_mut_with(locked, |d, l| {
+            if *l != 0 {
+                *d = 0;
+            }
+        });
+    }
+    log!(
+        "dither: {} pieces, density {}, generation {}, elapsed {:?}",
+        data.iter().filter(|v| **v != 0).count(),
+        best.density,
+        ga.generation,
+        start.elapsed()
+    );
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dither() {
+        // dark left half, bright right half
+        let map = Array2::from_elem((8, 16), true);
+        let target = Array2::from_shape_fn((8, 16), |(_, x)| (x >= 8) as u8 as f64);
+        let data = dither(&map, &target, None, None, Default::default());
+        let cover = img::lay(&data);
+        let left = cover.slice(s![.., ..8]).iter().filter(|v| **v).count();
+        let right = cover.slice(s![.., 8..]).iter().filter(|v| **v).count();
+        assert!(left <= 8, "{} cells covered in dark half", left);
+        assert!(right >= 48, "{} cells covered in bright half", right);
+        assert!(
+            ga::density(&img::box_blur(&target), &data)
+                > ga::density(&img::box_blur(&target), &Array2::zeros((8, 16)))
+        );
+        // box mean is kept up to date around changed cells only
+        let cover = Array2::from_elem((5, 6), false);
+        let flat = Array2::zeros((5, 6));
+        let mut density = Density {
+            target: &flat,
+            blur: img::box_blur(&cover.mapv(|v| v as u8 as f64)),
+            cover,
+        };
+        density.set(&[(0, 0), (0, 1), (1, 1), (4, 5)], true);
+        density.set(&[(2, 2), (0, 1)], true);
+        density.set(&[(1, 1), (3, 3)], false);
+        let expected = img::box_blur(&density.cover.mapv(|v| v as u8 as f64));
+        assert!(density
+            .blur
+            .iter()
+            .zip(&expected)
+            .all(|(a, b)| (a - b).abs() < 1e-9));
+
+        // error is per cell, so it stays in range on large frames
+        let bright = Array2::from_elem((400, 400), 1.0);
+        assert_eq!(ga::density(&bright, &Array2::zeros((400, 400))), -1000000);
+    }
+}
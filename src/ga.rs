@@ -1,5 +1,6 @@
 use crate::img;
 use crate::piece::TETROMINO;
+use crate::similarity;
 use ndarray::prelude::*;
 use ndarray::Array2;
 use rand::prelude::*;
@@ -16,12 +17,20 @@ use std::{cmp::max, cmp::min, fmt::Debug};
 pub struct Config {
     pub map: Array2<bool>,
     pub map_size: usize,
-    pub ref_map: Option<Array2<u8>>,
+    pub ref_map: Option<similarity::Reference>,
+    pub stability: Stability,
+    /// Pieces that every candidate keeps, anchor coded
+    pub locked: Option<Array2<u8>>,
+    /// Wanted share of covered cells around each cell (3x3 box), for dithering
+    pub target: Option<Array2<f64>>,
+    /// Fitness for covering each cell, 4 for every cell if not set
+    pub weight: Option<Array2<i32>>,
     pub size: usize,
     pub mutate: usize,
     pub crossover: usize,
     pub good_pool: usize,
-    pub score: fn(&Config, &img::EvalResult) -> i32,
+    /// Fitness from evaluation, similarity to `ref_map` and density
+    pub score: fn(&Config, &Candidate) -> i32,
     pub score_phase: i32,
     pub score_chunk: i32,
 }
@@ -32,6 +41,10 @@ impl Default for Config {
             map: Default::default(),
             map_size: 0,
             ref_map: None,
+            stability: Default::default(),
+            locked: None,
+            target: None,
+            weight: None,
             size: 64,
             mutate: 21,
             crossover: 16,
@@ -43,10 +56,23 @@ impl Default for Config {
     }
 }
 
+/// Reward for keeping pieces of `ref_map`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stability {
+    pub metric: similarity::Metric,
+    /// Fitness for each similarity point, filling a piece is worth 16. 0 is off
+    pub weight: i32,
+}
+
 #[derive(Clone)]
 pub struct Candidate {
     pub score: i32,
     pub raw_score: img::EvalResult,
+    /// Covered cells, weighted by `weight`
+    pub coverage: i32,
+    pub similarity: i32,
+    /// Fit of piece density to `target`, 0 is exact
+    pub density: i32,
     pub data: Arc<Array2<u8>>,
     hash: u64,
 }
@@ -81,9 +107,9 @@ impl GA {
     pub fn rescore(&mut self) {
         let cfg = &self.cfg;
         self.candidate.par_iter_mut().for_each(|c| {
-            c.score = (cfg.score)(cfg, &c.raw_score);
+            c.score = (cfg.score)(cfg, c);
         });
-        self.empty.score = (cfg.score)(cfg, &self.empty.raw_score);
+        self.empty.score = (cfg.score)(cfg, &self.empty);
     }
 
     pub fn step(&mut self) {
@@ -120,10 +146,10 @@ impl GA {
             .filter_map(|(t, mut rng)| match t {
                 TaskType::Mutate => {
                     let mut c = (*candidate[rng.gen_range(0..cfg.size)].data).clone();
-                    if !mutate(&cfg, &mut c, &mut rng) {
+                    if !mutate(cfg, &mut c, &mut rng) {
                         return None;
                     }
-                    Some(mk_candidate(&cfg, c))
+                    Some(mk_candidate(cfg, c))
                 }
                 TaskType::Crossover => {
                     let parent = rng.gen_range(0..cfg.size);
@@ -140,7 +166,7 @@ impl GA {
                     let mut c = (*candidate[parent].data).clone();
                     c.slice_mut(s![y1..=y2, x1..=x2])
                         .assign(&candidate[graft].data.slice(s![y1..=y2, x1..=x2]));
-                    Some(mk_candidate(&cfg, c))
+                    Some(mk_candidate(cfg, c))
                 }
             })
             .collect();
@@ -178,22 +204,66 @@ impl GA {
     }
 }
 
-fn mk_candidate(cfg: &Config, data: Array2<u8>) -> Candidate {
+fn mk_candidate(cfg: &Config, mut data: Array2<u8>) -> Candidate {
+    if let Some(locked) = &cfg.locked {
+        data.zip_mut_with(locked, |d, l| {
+            if *l != 0 {
+                *d = *l;
+            }
+        });
+    }
     let raw_score = img::eval(&cfg.map, &data);
+    let similarity = match &cfg.ref_map {
+        Some(ref_map) if cfg.stability.weight != 0 => {
+            similarity::score(cfg.stability.metric, &data, ref_map)
+        }
+        _ => 0,
+    };
+
+    let coverage = match (&cfg.weight, &raw_score) {
+        (Some(weight), img::EvalResult::Valid { .. }) => img::lay(&data)
+            .iter()
+            .zip(weight)
+            .filter(|(c, _)| **c)
+            .map(|(_, w)| *w)
+            .sum(),
+        (_, img::EvalResult::Valid { filled, .. }) => filled * 4,
+        _ => 0,
+    };
+    let density = match &cfg.target {
+        Some(target) => density(target, &data),
+        None => 0,
+    };
 
     let mut hasher = DefaultHasher::new();
     std::hash::Hash::hash_slice(img::lay(&data).as_slice().unwrap(), &mut hasher);
     let hash = hasher.finish();
 
-    Candidate {
-        score: (cfg.score)(cfg, &raw_score),
+    let mut c = Candidate {
+        score: 0,
         raw_score,
+        coverage,
+        similarity,
+        density,
         hash,
         data: Arc::new(data),
-    }
+    };
+    c.score = (cfg.score)(cfg, &c);
+    c
+}
+
+/// Fitness for each unit of mean squared density error, so that it never outweighs
+/// the base score of `score_dither` whatever the frame size
+const DENSITY_SCALE: f64 = 1000000.0;
+
+/// Mean squared difference between covered share around each cell and `target`, negated
+pub fn density(target: &Array2<f64>, data: &Array2<u8>) -> i32 {
+    let cover = img::box_blur(&img::lay(data).mapv(|v| v as u8 as f64));
+    let error: f64 = cover.iter().zip(target).map(|(c, t)| (c - t).powi(2)).sum();
+    -(error / target.len().max(1) as f64 * DENSITY_SCALE).round() as i32
 }
 
-pub fn score_grow(cfg: &Config, raw: &img::EvalResult) -> i32 {
+pub fn score_grow(cfg: &Config, c: &Candidate) -> i32 {
     let &img::EvalResult::Valid {
         chunk,
         filled,
@@ -202,7 +272,7 @@ pub fn score_grow(cfg: &Config, raw: &img::EvalResult) -> i32 {
         fragment_non4,
         hole,
         ..
-    } = raw
+    } = &c.raw_score
     else {
         return -100;
     };
@@ -210,13 +280,14 @@ pub fn score_grow(cfg: &Config, raw: &img::EvalResult) -> i32 {
     if filled == 0 {
         return 0;
     }
+    let stable = cfg.stability.weight * c.similarity;
 
     if chunk > cfg.score_chunk {
         return -101;
     }
 
     if cfg.score_phase == 0 {
-        if cfg.map_size % 4 == 0 {
+        if cfg.map_size.is_multiple_of(4) {
             if fragment_non4 > 0 {
                 return -102;
             }
@@ -225,17 +296,20 @@ pub fn score_grow(cfg: &Config, raw: &img::EvalResult) -> i32 {
                 return -102;
             }
         }
-        return max(0, filled * 4 - surface * 2 + 10 - 10 * fragment - 10 * hole);
+        max(
+            0,
+            c.coverage - surface * 2 + 10 - 10 * fragment - 10 * hole + stable,
+        )
     } else {
         // try hard mode
-        return max(
+        max(
             0,
-            filled * 4 - surface * 2 - fragment - fragment_non4 - 10 * hole,
-        );
+            c.coverage - surface * 2 - fragment - fragment_non4 - 10 * hole + stable,
+        )
     }
 }
 
-pub fn score_trim(cfg: &Config, raw: &img::EvalResult) -> i32 {
+pub fn score_trim(cfg: &Config, c: &Candidate) -> i32 {
     let &img::EvalResult::Valid {
         filled,
         surface,
@@ -243,7 +317,7 @@ pub fn score_trim(cfg: &Config, raw: &img::EvalResult) -> i32 {
         hole,
         edge,
         ..
-    } = raw
+    } = &c.raw_score
     else {
         return -104;
     };
@@ -253,17 +327,33 @@ pub fn score_trim(cfg: &Config, raw: &img::EvalResult) -> i32 {
     }
     let too_much_fill = max(0, filled - cfg.score_phase);
 
-    return max(
+    max(
         0,
         1000000 - 2 * edge - 5 * too_much_fill - surface - 50 * fragment - 10 * hole,
-    );
+    )
+}
+
+/// Density close to `cfg.target` wherever pieces go, any coverage is valid
+pub fn score_dither(cfg: &Config, c: &Candidate) -> i32 {
+    if !matches!(c.raw_score, img::EvalResult::Valid { .. }) {
+        return -105;
+    }
+    max(
+        0,
+        DENSITY_SCALE as i32 + c.density + cfg.stability.weight * c.similarity,
+    )
 }
 
 fn mutate(cfg: &Config, c: &mut Array2<u8>, rng: &mut dyn RngCore) -> bool {
     let &[h, w] = c.shape() else { unreachable!() };
     let piece_count = c.iter().filter(|i| **i != 0).count();
+    let is_locked = |pos: (usize, usize)| cfg.locked.as_ref().is_some_and(|l| l[pos] != 0);
+    let removable = c
+        .indexed_iter()
+        .filter(|(pos, v)| **v != 0 && !is_locked(*pos))
+        .count();
     let can_add = cfg.map_size - 4 * piece_count >= 4;
-    let can_remove = piece_count > 0;
+    let can_remove = removable > 0;
 
     if !can_add && !can_remove {
         return false;
@@ -276,7 +366,7 @@ fn mutate(cfg: &Config, c: &mut Array2<u8>, rng: &mut dyn RngCore) -> bool {
 
     if do_add {
         // Add piece
-        let stage = img::lay(&c);
+        let stage = img::lay(c);
         'outer: for _ in 0..3000 {
             let pos = rng.gen_range(0..(w * h));
             let (y, x) = (pos / w, pos % w);
@@ -293,14 +383,18 @@ fn mutate(cfg: &Config, c: &mut Array2<u8>, rng: &mut dyn RngCore) -> bool {
             c[(y, x)] = piece_type as u8;
             return true;
         }
-        return false;
+        false
     } else {
         // Remove piece
         // TODO: remove only outermost piece
-        let pos = rng.gen_range(0..piece_count);
-        let v = c.iter_mut().filter(|i| **i != 0).skip(pos).next().unwrap();
+        let pos = rng.gen_range(0..removable);
+        let (_, v) = c
+            .indexed_iter_mut()
+            .filter(|(pos, v)| **v != 0 && !is_locked(*pos))
+            .nth(pos)
+            .unwrap();
         *v = 0;
-        return true;
+        true
     }
 }
 
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+
+use ndarray::Array2;
+
+use crate::{img::Cells, piece};
+
+/// Largest centroid distance in cells to keep id of a piece that no longer overlaps
+const MAX_MOVE: f64 = 2.0;
+
+/// Persistent piece id for each covered cell of `data`.
+///
+/// Each piece keeps the id of the previous piece it overlaps the most, or the nearest one
+/// that moved at most `MAX_MOVE` cells. This is a greedy match, not an optimal assignment:
+/// pairs are taken from the largest overlap, then the shortest distance, then scan order,
+/// so when two pieces compete for one previous id the better match wins and the other
+/// takes its next free candidate. Other pieces get new ids above any id in `prev_id`.
+pub fn assign(prev_id: Option<&Array2<u32>>, data: &Array2<u8>) -> Array2<u32> {
+    let &[h, w] = data.shape() else {
+        unreachable!()
+    };
+    let prev_id = prev_id.filter(|p| p.shape() == data.shape());
+
+    let mut prev: BTreeMap<u32, Cells> = BTreeMap::new();
+    if let Some(prev_id) = prev_id {
+        for (pos, &id) in prev_id.indexed_iter() {
+            if id != 0 {
+                prev.entry(id).or_default().push(pos);
+            }
+        }
+    }
+    let current: Vec<_> = data
+        .indexed_iter()
+        .filter(|(_, v)| **v != 0)
+        .filter_map(|(pos, &v)| piece::cells(v, pos, (h, w)))
+        .collect();
+
+    // (overlap, distance) of each candidate pair
+    let mut pair = vec![];
+    for (i, cells) in current.iter().enumerate() {
+        let c = center(cells);
+        for (&id, prev_cells) in &prev {
+            let overlap = cells.iter().filter(|c| prev_cells.contains(c)).count();
+            let p = center(prev_cells);
+            let dist = (c.0 - p.0).hypot(c.1 - p.1);
+            if overlap > 0 || dist <= MAX_MOVE {
+                pair.push((overlap, dist, i, id));
+            }
+        }
+    }
+    pair.sort_by(|a, b| {
+        b.0.cmp(&a.0)
+            .then(a.1.total_cmp(&b.1))
+            .then((a.2, a.3).cmp(&(b.2, b.3)))
+    });
+
+    let mut id = vec![0; current.len()];
+    let mut used = vec![];
+    for (_, _, i, prev) in pair {
+        if id[i] == 0 && !used.contains(&prev) {
+            id[i] = prev;
+            used.push(prev);
+        }
+    }
+    let mut next = prev.keys().max().map_or(1, |m| m + 1);
+    let mut out = Array2::zeros(data.raw_dim());
+    for (cells, mut id) in current.iter().zip(id) {
+        if id == 0 {
+            id = next;
+            next += 1;
+        }
+        for c in cells {
+            out[*c] = id;
+        }
+    }
+    out
+}
+
+fn center(cells: &[(usize, usize)]) -> (f64, f64) {
+    let n = cells.len().max(1) as f64;
+    let (sy, sx) = cells
+        .iter()
+        .fold((0, 0), |(sy, sx), (y, x)| (sy + y, sx + x));
+    (sy as f64 / n, sx as f64 / n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    #[test]
+    fn test_assign() {
+        let data = array![
+            [1, 0, 0, 1, 0, 0, 0, 0, 0], //
+            [0, 0, 0, 0, 0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0],
+        ];
+        let id = assign(None, &data);
+        assert_eq!(id[(0, 0)], 1);
+        assert_eq!(id[(0, 3)], 2);
+        // both pieces move one cell
+        let data = array![
+            [0, 0, 0, 0, 0, 0, 0, 0, 0], //
+            [1, 0, 0, 0, 1, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0],
+        ];
+        let moved = assign(Some(&id), &data);
+        assert_eq!(moved[(1, 0)], 1);
+        assert_eq!(moved[(1, 4)], 2);
+        // new piece far away from both
+        let data = array![
+            [0, 0, 0, 0, 0, 0, 0, 0, 0], //
+            [0, 0, 0, 0, 0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0, 2, 0, 0, 0],
+        ];
+        let far = assign(Some(&id), &data);
+        assert_eq!(far[(2, 8)], 3);
+    }
+
+    #[test]
+    fn test_contention() {
+        let prev_id = array![
+            [1, 1, 0, 0, 0], //
+            [1, 1, 0, 0, 0],
+        ];
+        // both bars overlap the square, the lower one by more cells
+        let data = array![
+            [0, 2, 0, 0, 0], //
+            [2, 0, 0, 0, 0],
+        ];
+        let id = assign(Some(&prev_id), &data);
+        assert_eq!(id.row(1).to_vec(), vec![1, 1, 1, 1, 0]);
+        assert_eq!(id.row(0).to_vec(), vec![0, 2, 2, 2, 2]);
+        // scanning the other way round gives the same match
+        let flipped = assign(
+            Some(&prev_id.slice(s![..;-1, ..]).to_owned()),
+            &array![
+                [2, 0, 0, 0, 0], //
+                [0, 2, 0, 0, 0],
+            ],
+        );
+        assert_eq!(flipped.row(0).to_vec(), vec![1, 1, 1, 1, 0]);
+    }
+}
@@ -1,6 +1,7 @@
-use crate::piece::TETROMINO;
+use crate::piece::{self, TETROMINO};
 use ndarray::{s, Array2, ArrayView2};
 use std::cmp::{max, min};
+use std::collections::BTreeMap;
 
 fn fill(stage: &mut Array2<u8>, start: (usize, usize), to: u8) -> isize {
     let &[h, w] = stage.shape() else {
@@ -29,7 +30,7 @@ fn fill(stage: &mut Array2<u8>, start: (usize, usize), to: u8) -> isize {
             stack.push((y, x + 1));
         }
     }
-    return count;
+    count
 }
 
 pub struct Segment {
@@ -96,6 +97,7 @@ pub enum EvalResult {
     Invalid,
 }
 
+#[allow(clippy::nonminimal_bool, clippy::collapsible_if)]
 pub fn eval(map: &Array2<bool>, data: &Array2<u8>) -> EvalResult {
     let &[h, w] = map.shape() else { unreachable!() };
     let mut stage: Array2<u8> = Array2::zeros(map.raw_dim());
@@ -228,7 +230,7 @@ pub fn eval(map: &Array2<bool>, data: &Array2<u8>) -> EvalResult {
 
     // 0 = unreachable, 1=empty, 2 = filled
 
-    return EvalResult::Valid {
+    EvalResult::Valid {
         chunk,
         filled,
         surface,
@@ -236,7 +238,19 @@ pub fn eval(map: &Array2<bool>, data: &Array2<u8>) -> EvalResult {
         fragment_non4,
         hole,
         edge,
-    };
+    }
+}
+
+/// Mean of the 3x3 box around each cell, over cells inside the array
+pub fn box_blur(a: &Array2<f64>) -> Array2<f64> {
+    let &[h, w] = a.shape() else { unreachable!() };
+    Array2::from_shape_fn((h, w), |(y, x)| {
+        let area = a.slice(s![
+            y.saturating_sub(1)..min(y + 2, h),
+            x.saturating_sub(1)..min(x + 2, w)
+        ]);
+        area.sum() / area.len() as f64
+    })
 }
 
 pub fn lay(data: &Array2<u8>) -> Array2<bool> {
@@ -260,6 +274,78 @@ pub fn lay(data: &Array2<u8>) -> Array2<bool> {
     stage
 }
 
+/// Unique piece id (from 1) and piece type for each covered cell
+pub fn label(data: &Array2<u8>) -> (Array2<u32>, Array2<u8>) {
+    let &[h, w] = data.shape() else {
+        unreachable!()
+    };
+    let mut label = Array2::zeros(data.raw_dim());
+    let mut kind = Array2::zeros(data.raw_dim());
+    let mut counter = 0;
+    for ((y, x), &v) in data.indexed_iter() {
+        if v == 0 {
+            continue;
+        }
+        counter += 1;
+        let Some(cells) = piece::cells(v, (y, x), (h, w)) else {
+            continue;
+        };
+        for c in cells {
+            label[c] = counter;
+            kind[c] = v;
+        }
+    }
+    (label, kind)
+}
+
+pub type Cells = Vec<(usize, usize)>;
+
+/// Why `from_label` dropped a label
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Invalid {
+    /// Cells do not form a tetromino
+    Shape,
+    /// Anchor cell is taken by another piece
+    Collision,
+}
+
+/// Convert per-cell piece labels back to anchor encoding. Labels `<= 0` are empty.
+/// Also returns the labels that were dropped.
+pub fn from_label(label: &Array2<i64>) -> (Array2<u8>, Vec<(i64, Invalid, Cells)>) {
+    let mut group: BTreeMap<i64, Cells> = BTreeMap::new();
+    for (pos, &v) in label.indexed_iter() {
+        if v > 0 {
+            group.entry(v).or_default().push(pos);
+        }
+    }
+    let mut data = Array2::zeros(label.raw_dim());
+    let mut invalid = vec![];
+    'outer: for (id, cells) in group {
+        let y0 = cells.iter().map(|c| c.0).min().unwrap();
+        let x0 = cells.iter().map(|c| c.1).min().unwrap();
+        let mut shape: Vec<_> = cells.iter().map(|(y, x)| (y - y0, x - x0)).collect();
+        shape.sort();
+        for (v, piece) in TETROMINO.iter().enumerate().skip(1) {
+            let mut piece: Vec<_> = piece
+                .iter()
+                .map(|&(dy, dx)| (dy as usize, dx as usize))
+                .collect();
+            piece.sort();
+            if piece == shape {
+                // anchor can be shared with another piece
+                if data[(y0, x0)] != 0 {
+                    invalid.push((id, Invalid::Collision, cells));
+                } else {
+                    data[(y0, x0)] = v as u8;
+                }
+                continue 'outer;
+            }
+        }
+        invalid.push((id, Invalid::Shape, cells));
+    }
+    (data, invalid)
+}
+
 pub fn transfer(map: ArrayView2<bool>, ref_map: ArrayView2<u8>) -> Array2<u8> {
     let mut c = Array2::zeros(map.raw_dim());
     let mut stage = Array2::from_elem(map.raw_dim(), false);
@@ -285,6 +371,31 @@ pub fn transfer(map: ArrayView2<bool>, ref_map: ArrayView2<u8>) -> Array2<u8> {
     c
 }
 
+/// Pieces of `base`, plus pieces of `extra` that do not overlap them
+pub fn overlay(base: &Array2<u8>, extra: &Array2<u8>) -> Array2<u8> {
+    let &[h, w] = base.shape() else {
+        unreachable!()
+    };
+    let mut c = base.clone();
+    let mut stage = lay(base);
+    for ((y, x), &v) in extra.indexed_iter() {
+        if v == 0 || c[(y, x)] != 0 {
+            continue;
+        }
+        let Some(cells) = piece::cells(v, (y, x), (h, w)) else {
+            continue;
+        };
+        if cells.iter().any(|c| stage[*c]) {
+            continue;
+        }
+        for cell in cells {
+            stage[cell] = true;
+        }
+        c[(y, x)] = v;
+    }
+    c
+}
+
 /// For visualizing
 pub fn dump(map: &Array2<bool>, data: &Array2<u8>) -> Vec<String> {
     const DUMP_COLOR: &[u8] = b"0123456789@#=+*%$ikfgreqzan";
@@ -425,6 +536,50 @@ mod tests {
         );
     }
     #[test]
+    fn test_label() {
+        let data = array![
+            [1, 0, 3], //
+            [0, 0, 0],
+            [0, 0, 0],
+            [0, 0, 0],
+        ];
+        let (label, kind) = label(&data);
+        assert_eq!(
+            label,
+            array![
+                [1, 1, 2], //
+                [1, 1, 2],
+                [0, 0, 2],
+                [0, 0, 2],
+            ]
+        );
+        assert_eq!(kind[(1, 1)], 1);
+        assert_eq!(kind[(3, 2)], 3);
+    }
+    #[test]
+    fn test_from_label() {
+        let label = array![
+            [1, 1, 2, 0], //
+            [1, 1, 2, 0],
+            [0, 2, 2, 0],
+            [3, 3, 3, 0],
+        ];
+        let (data, invalid) = from_label(&label);
+        assert_eq!(
+            data,
+            array![
+                [1, 4, 0, 0], //
+                [0, 0, 0, 0],
+                [0, 0, 0, 0],
+                [0, 0, 0, 0],
+            ]
+        );
+        assert_eq!(
+            invalid,
+            vec![(3, Invalid::Shape, vec![(3, 0), (3, 1), (3, 2)])]
+        );
+    }
+    #[test]
     fn test_maphole() {
         let map = array![
             [1, 1, 1], //
@@ -1,326 +1,219 @@
-use std::{
-    cmp::max,
-    collections::VecDeque,
-    time::{Duration, Instant},
-};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use anyhow::{anyhow, bail, Context, Result};
-use clap::Parser;
-use ndarray::prelude::*;
-use ndarray_npy::{NpzReader, NpzWriter};
-use rayon::prelude::{IntoParallelRefMutIterator, ParallelIterator};
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
 
+/// Progress goes to stderr instead when stdout carries frame data
+static LOG_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Progress output
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if crate::LOG_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+mod batch;
+mod check;
+mod cleanup;
+mod dither;
 mod ga;
+mod identity;
 mod img;
+mod motion;
+mod npz;
 mod piece;
+mod preprocess;
+mod remainder;
+mod render;
+mod similarity;
+mod smooth;
+mod solve;
+mod split;
+mod stream;
+mod track;
+mod tween;
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Input .npz
-    file: String,
+    #[arg(required = true)]
+    file: Option<String>,
     /// Reference .npz (output file from last frame)
     ref_file: Option<String>,
     #[arg(short)]
     output_path: Option<String>,
+    /// Arrays to write to output .npz
+    #[arg(long, global = true, value_enum, value_delimiter = ',', default_values_t = npz::OutputArray::ALL)]
+    arrays: Vec<npz::OutputArray>,
+    #[command(flatten)]
+    opts: solve::Options,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Solve numbered frames (NNNN.npz into NNNN_out.npz), each against the previous output.
+    /// Frames that are already done are skipped.
+    Batch {
+        /// Working directory
+        dir: PathBuf,
+        /// Start from frame #n
+        #[arg(short, long, default_value_t = 0)]
+        from: usize,
+        /// Stop after frame #n
+        #[arg(short, long)]
+        to: Option<usize>,
+        /// Solve next frame at the same time against a provisional reference
+        #[arg(long)]
+        pipeline: bool,
+    },
+    /// Re-solve frames of a solved sequence (NNNN.npz, NNNN_out.npz) to reduce piece churn
+    Smooth {
+        /// Working directory
+        dir: PathBuf,
+        /// Start from frame #n
+        #[arg(short, long, default_value_t = 0)]
+        from: usize,
+        /// Stop after frame #n, default is the whole clip
+        #[arg(short, long)]
+        to: Option<usize>,
+        /// Number of passes over the window
+        #[arg(long, default_value_t = 1)]
+        passes: usize,
+        #[arg(long, value_enum, default_value_t = smooth::Direction::Both)]
+        direction: smooth::Direction,
+    },
+    /// Threshold grayscale frames into numbered inputs (NNNN.npz) for batch
+    Preprocess {
+        /// Working directory
+        dir: PathBuf,
+        /// Input frames: .pgm, .npz with a `gray` array, or raw 8-bit gray (needs --size)
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Number frames from #n
+        #[arg(short, long, default_value_t = 0)]
+        from: usize,
+        /// Raw frame size in pixel, WIDTHxHEIGHT
+        #[arg(long, value_parser = preprocess::parse_size)]
+        size: Option<(usize, usize)>,
+        #[command(flatten)]
+        prep: preprocess::Options,
+    },
+    /// Solve raw frames read from stdin (e.g. ffmpeg -f rawvideo), each against the
+    /// previous one, streaming piece arrays or rendered frames to stdout
+    Stream(stream::Config),
+    /// Export piece keyframes between two consecutive output files for animation
+    Tween {
+        /// Output .npz of previous frame
+        prev: String,
+        /// Output .npz of current frame
+        next: String,
+        /// Default is current frame's name with _tween.npz
+        #[arg(short)]
+        output_path: Option<String>,
+    },
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let map = {
-        let fp = std::fs::File::open(&args.file).with_context(|| anyhow!("file not found"))?;
-        let mut npz = NpzReader::new(fp).with_context(|| anyhow!("cannot open npz"))?;
-        let raw: Array2<u8> = (npz.by_name("map"))
-            .or_else(|_| npz.by_name("map.npy"))
-            .with_context(|| anyhow!("map var not found"))?;
-        raw.mapv(|x| x != 0)
-    };
-    let ref_map = if let Some(ref_file) = &args.ref_file {
-        let fp = std::fs::File::open(ref_file).with_context(|| anyhow!("ref file not found"))?;
-        let mut npz = NpzReader::new(fp).with_context(|| anyhow!("cannot open ref npz"))?;
-        let raw: Array2<u8> = npz
-            .by_name("piece")
-            .with_context(|| anyhow!("piece var not found"))?;
-        Some(raw)
-    } else {
-        None
-    };
-
-    let mut composite: Array2<u8> = Array2::zeros(map.raw_dim());
-
-    for seg in img::segment(&map) {
-        if seg.map_size < 4 {
-            continue;
-        }
-        let map = trim_remainder(&seg);
-        let &[h, w] = map.shape() else { unreachable!() };
-
-        let ref_map = ref_map
-            .as_ref()
-            .map(|m| m.slice(s![seg.y..(seg.y + h), seg.x..(seg.x + w),]));
-
-        let goal = map.iter().map(|x| *x as i32).sum::<i32>() / 4 * 4;
-        let mut candidate = vec![];
-        let mut last_ga = None;
-        let mut success = false;
-        let start = Instant::now();
-        for seed in 0..20 {
-            // transfer ref
-            let ref_map = ref_map.map(|ref_map| {
-                for seed2 in 0..10 {
-                    match trim(map.view(), ref_map.view(), seed + 1000 * seed2) {
-                        Ok(out) => return out,
-                        Err(_) => continue,
-                    }
-                }
-                std::process::exit(-1);
-            });
+    if let Some(Command::Batch {
+        dir,
+        from,
+        to,
+        pipeline,
+    }) = &args.command
+    {
+        return batch::run(dir, *from, *to, *pipeline, &args.opts, &args.arrays);
+    }
 
-            match grow(
-                map.view(),
-                ref_map.as_ref().map(|x| x.view()),
-                seed,
-                goal,
-                false,
-            ) {
-                Ok(ga) => {
-                    success = true;
-                    candidate.push(ga.candidate[0].clone());
-                    last_ga = Some(ga);
-                    if candidate.len() >= 3 || ref_map.is_none() {
-                        break;
-                    }
-                }
-                Err(ga) => {
-                    last_ga = Some(ga);
-                }
-            }
-        }
-        println!("elapsed: {:?}", start.elapsed());
-        let ga = last_ga.as_ref().unwrap();
-        if success {
-            println!("generation: {}", ga.generation);
-            for row in img::dump(&ga.cfg.map, &candidate[0].data) {
-                println!("|{}|", row);
-            }
-            println!("  {:?} = {}", candidate[0].raw_score, ga.candidate[0].score);
-        } else {
-            println!("{:?}", ga);
-            println!("Failed");
-            // add failed candidate for base line
-            candidate.push(ga.candidate[0].clone());
+    if let Some(Command::Preprocess {
+        files,
+        dir,
+        from,
+        size,
+        prep,
+    }) = &args.command
+    {
+        return preprocess::run(dir, files, *from, *size, prep);
+    }
 
-            // try hard mode
-            for seed in 0..5 {
-                // transfer ref
-                let ref_map = ref_map.map(|ref_map| {
-                    for seed2 in 0..10 {
-                        match trim(map.view(), ref_map.view(), seed + 1000 * seed2) {
-                            Ok(out) => return out,
-                            Err(_) => continue,
-                        }
-                    }
-                    std::process::exit(-1);
-                });
+    if let Some(Command::Stream(cfg)) = &args.command {
+        LOG_STDERR.store(true, Ordering::Relaxed);
+        let n = stream::run(
+            cfg,
+            &args.opts,
+            std::io::stdin().lock(),
+            std::io::stdout().lock(),
+        )?;
+        log!("stream: {} frames", n);
+        return Ok(());
+    }
 
-                let goal = seg.map.iter().map(|x| *x as i32).sum::<i32>() / 4 * 4;
-                match grow(
-                    seg.map.view(),
-                    ref_map.as_ref().map(|x| x.view()),
-                    seed,
-                    goal,
-                    true,
-                ) {
-                    Ok(ga) => {
-                        candidate.push(ga.candidate[0].clone());
-                        break;
-                    }
-                    Err(ga) => {
-                        candidate.push(ga.candidate[0].clone());
-                    }
-                }
+    if let Some(Command::Smooth {
+        dir,
+        from,
+        to,
+        passes,
+        direction,
+    }) = &args.command
+    {
+        let mut frames = smooth::load(dir, *from, *to)?;
+        log!("smooth: {} frames", frames.len());
+        for i in 0..*passes {
+            let changed = smooth::pass(&mut frames, *direction, &args.opts, |f| {
+                npz::write_output(&f.output, &f.input.map, &f.data, &f.id, &args.arrays)
+            })?;
+            log!("smooth: pass {} changed {} frames", i + 1, changed);
+            if changed == 0 {
+                break;
             }
         }
-
-        // rank candidate by similarity
-        candidate.par_iter_mut().for_each(|c| {
-            let similarity = ref_map
-                .map(|ref_map| {
-                    c.data
-                        .iter()
-                        .zip(ref_map)
-                        .map(|(a, b)| (*a != 0 && a == b) as i32)
-                        .sum()
-                })
-                .unwrap_or(0i32);
-            c.score = if let img::EvalResult::Valid { filled, .. } = c.raw_score {
-                filled * 10 + similarity
-            } else {
-                0
-            };
-        });
-        candidate.sort_by_key(|c| -c.score);
-
-        composite
-            .slice_mut(s![seg.y..(seg.y + h), seg.x..(seg.x + w),])
-            .zip_mut_with(&candidate[0].data, |comp, diff| {
-                *comp += diff;
-            });
+        return Ok(());
     }
-    println!("Final");
-    for row in img::dump(&map, &composite) {
-        println!("|{}|", row);
+
+    if let Some(Command::Tween {
+        prev,
+        next,
+        output_path,
+    }) = &args.command
+    {
+        return tween::run(prev, next, output_path.as_deref());
     }
 
+    let file = args.file.context("input file is required")?;
     let output_name: String = args.output_path.unwrap_or_else(|| {
-        if args.file.ends_with(".npz") {
-            let stem = &args.file[..(args.file.len() - 4)];
+        if file.ends_with(".npz") {
+            let stem = &file[..(file.len() - 4)];
             format!("{}_out.npz", stem)
         } else {
-            format!("{}_out.npz", &args.file)
+            format!("{}_out.npz", &file)
         }
     });
-    let fp = std::fs::File::create(output_name).with_context(|| "Cannot create output file")?;
-    let mut npz = NpzWriter::new(fp);
-    npz.add_array("piece", &composite).unwrap();
-    npz.finish().with_context(|| "Cannot write output file")?;
-
-    Ok(())
-}
-
-fn trim_remainder(seg: &img::Segment) -> Array2<bool> {
-    let &[h, w] = seg.map.shape() else {
-        unreachable!()
-    };
-    if seg.map_size % 4 != 0 {
-        let mut map = seg.map.clone();
-        let mut need_remove = seg.map_size % 4;
-        'outer: for y in 0..h {
-            for x in 0..w {
-                if map[(y, x)] {
-                    map[(y, x)] = false;
-                    if img::segment(&map).len() != 1 {
-                        map[(y, x)] = true;
-                        continue;
-                    }
-                    need_remove -= 1;
-                    if need_remove == 0 {
-                        break 'outer;
-                    }
-                }
-            }
-        }
-        map
-    } else {
-        seg.map.clone()
-    }
-}
-
-fn trim(map: ArrayView2<bool>, ref_map: ArrayView2<u8>, seed: u64) -> Result<Array2<u8>> {
-    let new_ref = img::transfer(map.view(), ref_map);
-    let mut ga = ga::GA::new(
-        ga::Config {
-            map: map.to_owned(),
-            score: ga::score_trim,
-            ..Default::default()
-        },
-        seed,
-        Some(new_ref),
-    );
-    ga.cfg.score_phase = ga.cfg.map_size as i32;
-    let success = loop {
-        if ga.generation % 100 == 0 {
-            ga.cfg.score_phase -= 4;
-            ga.rescore();
-        }
-        ga.step();
-        if matches!(ga.candidate[0].raw_score,
-            img::EvalResult::Valid { fragment, .. } if fragment <= 1)
-        {
-            break true;
-        }
-        if ga.generation >= 100000 {
-            break false;
-        }
-    };
-    if !success {
-        bail!("trim failed")
-    }
-    println!("trim: {}", success);
-    for row in img::dump(&ga.cfg.map, &ga.candidate[0].data) {
-        println!("|{}|", row);
-    }
-    Ok((*ga.candidate[0].data).clone())
-}
-
-fn grow(
-    map: ArrayView2<bool>,
-    ref_map: Option<ArrayView2<u8>>,
-    seed: u64,
-    goal: i32,
-    try_hard: bool,
-) -> Result<ga::GA, ga::GA> {
-    let mut ga = ga::GA::new(
-        ga::Config {
-            map: map.to_owned(),
-            ref_map: ref_map.map(|m| m.to_owned()),
-            ..Default::default()
-        },
-        seed,
-        None,
-    );
-    if let Some(ref_map) = ref_map {
-        ga.add_candidate(ref_map.to_owned());
-        if ga.candidate[0].score < 0 {
-            ga.cfg.score_chunk = match ga.candidate[0].raw_score {
-                img::EvalResult::Valid { chunk, .. } => max(1, chunk),
-                _ => 1,
-            };
-            ga.rescore();
-        }
-    }
-    let mut last_score: VecDeque<_> = [-1, -2, -3].into();
-    let mut status_timer = Instant::now();
-    loop {
-        ga.step();
-        if matches!(ga.candidate[0].raw_score,
-            img::EvalResult::Valid { filled, .. } if filled >= goal)
-        {
-            return Ok(ga);
-        }
-        if ga.generation >= 100000 {
-            return Err(ga);
-        }
-        let score = ga.candidate[0].score;
-        if ga.generation % 1000 == 0 {
-            if last_score.iter().all(|v| *v == score) {
-                println!("seed: {} stuck @ gen: {}", seed, ga.generation);
-                if try_hard {
-                    if ga.cfg.score_phase == 1 {
-                        return Err(ga);
-                    }
-                    ga.cfg.score_phase = 1;
-                    ga.rescore();
-                    continue;
-                } else {
-                    return Err(ga);
-                }
-            }
-            last_score.pop_front();
-            last_score.push_back(score);
-        }
-
-        // show progress
-        if ga.generation % 1000 == 0 && status_timer.elapsed() > Duration::from_secs(3) {
-            println!(
-                "generation: {}, score: {}",
-                ga.generation, ga.candidate[0].score
-            );
-            for row in img::dump(&ga.cfg.map, &ga.candidate[0].data) {
-                println!("|{}|", row);
-            }
-            println!("   {:?}", ga.candidate[0].raw_score);
-            status_timer = Instant::now();
-        }
+    // 3d map is a whole sequence
+    if let Some(inputs) = npz::read_stack(&file)? {
+        println!("sequence: {} frames", inputs.len());
+        return batch::run_stack(
+            &inputs,
+            args.ref_file.as_deref().map(Path::new),
+            output_name.as_ref(),
+            &args.opts,
+            &args.arrays,
+        );
     }
+    batch::solve_file(
+        file.as_ref(),
+        args.ref_file.as_deref().map(Path::new),
+        output_name.as_ref(),
+        &args.opts,
+        &args.arrays,
+    )
 }
@@ -0,0 +1,109 @@
+use crate::piece;
+use ndarray::{Array2, ArrayView2};
+
+/// Translation `(dy, dx)` within `radius` around `center` that lets the most reference pieces
+/// fit in `map`. Prefers shift closer to `center` on ties.
+pub fn estimate(
+    map: ArrayView2<bool>,
+    ref_map: ArrayView2<u8>,
+    center: (isize, isize),
+    radius: isize,
+) -> (isize, isize) {
+    let pieces: Vec<_> = ref_map
+        .indexed_iter()
+        .filter(|(_, v)| **v != 0)
+        .map(|(pos, v)| (pos, *v))
+        .collect();
+    let fit = |d: (isize, isize)| {
+        pieces
+            .iter()
+            .filter(|(pos, v)| fits(map, *v, *pos, d))
+            .count()
+    };
+    let mut best = center;
+    let mut best_key = (fit(best), 0);
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let d = (center.0 + dy, center.1 + dx);
+            let key = (fit(d), -(dy.abs() + dx.abs()));
+            if key > best_key {
+                best = d;
+                best_key = key;
+            }
+        }
+    }
+    best
+}
+
+/// Move pieces by `(dy, dx)`, dropping pieces which go out of bound
+pub fn shift(ref_map: ArrayView2<u8>, (dy, dx): (isize, isize)) -> Array2<u8> {
+    let &[h, w] = ref_map.shape() else {
+        unreachable!()
+    };
+    let mut out = Array2::zeros(ref_map.raw_dim());
+    for ((y, x), &v) in ref_map.indexed_iter() {
+        if v == 0 {
+            continue;
+        }
+        let Some(pos) = offset((y, x), (dy, dx)) else {
+            continue;
+        };
+        if piece::cells(v, pos, (h, w)).is_some() {
+            out[pos] = v;
+        }
+    }
+    out
+}
+
+/// Move cells by `(dy, dx)`, dropping cells which go out of bound
+pub fn shift_cells(mask: &Array2<bool>, d: (isize, isize)) -> Array2<bool> {
+    let mut out = Array2::from_elem(mask.raw_dim(), false);
+    for (pos, _) in mask.indexed_iter().filter(|(_, v)| **v) {
+        if let Some(pos) = offset(pos, d).filter(|p| p.0 < mask.nrows() && p.1 < mask.ncols()) {
+            out[pos] = true;
+        }
+    }
+    out
+}
+
+fn offset((y, x): (usize, usize), (dy, dx): (isize, isize)) -> Option<(usize, usize)> {
+    Some((y.checked_add_signed(dy)?, x.checked_add_signed(dx)?))
+}
+
+fn fits(map: ArrayView2<bool>, v: u8, pos: (usize, usize), d: (isize, isize)) -> bool {
+    let &[h, w] = map.shape() else { unreachable!() };
+    let Some(pos) = offset(pos, d) else {
+        return false;
+    };
+    match piece::cells(v, pos, (h, w)) {
+        Some(cells) => cells.iter().all(|c| map[*c]),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    #[test]
+    fn test_estimate() {
+        let ref_map = array![
+            [1, 0, 0, 0, 0], //
+            [0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0],
+        ];
+        let map = array![
+            [0, 0, 0, 0, 0], //
+            [0, 0, 1, 1, 0],
+            [0, 0, 1, 1, 0],
+        ]
+        .mapv(|x| x != 0);
+        let d = estimate(map.view(), ref_map.view(), (0, 0), 4);
+        assert_eq!(d, (1, 2));
+        assert_eq!(shift(ref_map.view(), d)[(1, 2)], 1);
+        let back = shift_cells(&map, (-1, -2));
+        assert_eq!(back.iter().filter(|v| **v).count(), 4);
+        assert!(back[(0, 0)] && !shift_cells(&map, (0, 3))[(1, 2)]);
+    }
+}
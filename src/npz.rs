@@ -0,0 +1,424 @@
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use ndarray::{prelude::*, OwnedRepr};
+use ndarray_npy::{NpzReader, NpzWriter};
+
+use crate::{img, piece::TETROMINO, solve, tween};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum OutputArray {
+    /// Piece type at its anchor cell
+    Piece,
+    /// Unique piece id for each covered cell
+    Label,
+    /// Piece type for each covered cell
+    Type,
+    /// Map cells that are left uncovered
+    Unfilled,
+    /// Piece id for each covered cell, kept by the same piece across frames
+    Id,
+}
+
+impl OutputArray {
+    pub const ALL: [Self; 5] = [
+        Self::Piece,
+        Self::Label,
+        Self::Type,
+        Self::Unfilled,
+        Self::Id,
+    ];
+}
+
+/// Read `map` with optional source brightness `gray`, cell `weight`, and `lock` and `empty`
+/// constraints
+pub fn read_input(path: impl AsRef<Path>) -> Result<solve::Input> {
+    let fp = File::open(path).with_context(|| anyhow!("file not found"))?;
+    let mut npz = NpzReader::new(fp).with_context(|| anyhow!("cannot open npz"))?;
+    let raw: Array2<u8> = (npz.by_name("map"))
+        .or_else(|_| npz.by_name("map.npy"))
+        .with_context(|| anyhow!("map var not found"))?;
+    // optional source brightness
+    let gray: Option<Array2<u8>> = (npz.by_name("gray"))
+        .or_else(|_| npz.by_name("gray.npy"))
+        .ok();
+    check_shape("gray", gray.as_ref(), raw.shape())?;
+    let lock = read_int(&mut npz, "lock").or_else(|| read_int(&mut npz, "lock.npy"));
+    let empty = read_int(&mut npz, "empty").or_else(|| read_int(&mut npz, "empty.npy"));
+    let weight = read_float(&mut npz, "weight").or_else(|| read_float(&mut npz, "weight.npy"));
+    check_shape("lock", lock.as_ref(), raw.shape())?;
+    check_shape("empty", empty.as_ref(), raw.shape())?;
+    check_shape("weight", weight.as_ref(), raw.shape())?;
+    Ok(solve::Input {
+        map: raw.mapv(|x| x != 0),
+        gray,
+        weight,
+        lock: lock.map(|a| a.mapv(lock_piece)),
+        empty: empty.map(|a| a.mapv(|v| v != 0)),
+    })
+}
+
+/// Fail if optional array `name` does not have the shape of `map`
+fn check_shape<T, D: Dimension>(name: &str, a: Option<&Array<T, D>>, map: &[usize]) -> Result<()> {
+    match a {
+        Some(a) if a.shape() != map => {
+            bail!("{} has shape {:?}, map has {:?}", name, a.shape(), map)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Anything but a piece type is left unlocked
+fn lock_piece(v: i64) -> u8 {
+    if v < TETROMINO.len() as i64 {
+        v.max(0) as u8
+    } else {
+        0
+    }
+}
+
+/// Read a sequence stacked as 3d arrays (frames × h × w), `None` if `map` is not 3d
+pub fn read_stack(path: impl AsRef<Path>) -> Result<Option<Vec<solve::Input>>> {
+    let fp = File::open(path).with_context(|| anyhow!("file not found"))?;
+    let mut npz = NpzReader::new(fp).with_context(|| anyhow!("cannot open npz"))?;
+    let Ok(raw) = (npz.by_name::<OwnedRepr<u8>, Ix3>("map"))
+        .or_else(|_| npz.by_name::<OwnedRepr<u8>, Ix3>("map.npy"))
+    else {
+        return Ok(None);
+    };
+    if raw.len_of(Axis(0)) == 0 {
+        bail!("map has no frames");
+    }
+    let gray: Option<Array3<u8>> = (npz.by_name("gray"))
+        .or_else(|_| npz.by_name("gray.npy"))
+        .ok();
+    let lock: Option<Array3<i64>> =
+        read_int(&mut npz, "lock").or_else(|| read_int(&mut npz, "lock.npy"));
+    let empty: Option<Array3<i64>> =
+        read_int(&mut npz, "empty").or_else(|| read_int(&mut npz, "empty.npy"));
+    let weight: Option<Array3<f64>> =
+        read_float(&mut npz, "weight").or_else(|| read_float(&mut npz, "weight.npy"));
+    check_shape("gray", gray.as_ref(), raw.shape())?;
+    check_shape("lock", lock.as_ref(), raw.shape())?;
+    check_shape("empty", empty.as_ref(), raw.shape())?;
+    check_shape("weight", weight.as_ref(), raw.shape())?;
+    fn frame<T>(a: &Option<Array3<T>>, i: usize) -> Option<ArrayView2<'_, T>> {
+        a.as_ref().map(|a| a.index_axis(Axis(0), i))
+    }
+    Ok(Some(
+        (0..raw.len_of(Axis(0)))
+            .map(|i| solve::Input {
+                map: raw.index_axis(Axis(0), i).mapv(|x| x != 0),
+                gray: frame(&gray, i).map(|a| a.to_owned()),
+                weight: frame(&weight, i).map(|a| a.to_owned()),
+                lock: frame(&lock, i).map(|a| a.mapv(lock_piece)),
+                empty: frame(&empty, i).map(|a| a.mapv(|v| v != 0)),
+            })
+            .collect(),
+    ))
+}
+
+/// Read `gray` frames, 2d or stacked 3d
+pub fn read_gray(path: impl AsRef<Path>) -> Result<Vec<Array2<u8>>> {
+    let fp = File::open(path).with_context(|| anyhow!("file not found"))?;
+    let mut npz = NpzReader::new(fp).with_context(|| anyhow!("cannot open npz"))?;
+    if let Ok(gray) = (npz.by_name::<OwnedRepr<u8>, Ix3>("gray"))
+        .or_else(|_| npz.by_name::<OwnedRepr<u8>, Ix3>("gray.npy"))
+    {
+        return Ok(gray.outer_iter().map(|a| a.to_owned()).collect());
+    }
+    let gray: Array2<u8> = (npz.by_name("gray"))
+        .or_else(|_| npz.by_name("gray.npy"))
+        .with_context(|| anyhow!("gray var not found"))?;
+    Ok(vec![gray])
+}
+
+/// Reference layout read from an output file
+pub struct Reference {
+    pub data: Array2<u8>,
+    /// Map cells the layout left uncovered, if the file has them
+    pub unfilled: Option<Array2<bool>>,
+    /// Persistent piece ids, if the file has them
+    pub id: Option<Array2<u32>>,
+}
+
+/// Read reference layout from `label` (possibly hand edited) or `piece` array, which must
+/// have the shape of `map` if given, along with its `unfilled` cells and piece `id`s
+pub fn read_ref(path: impl AsRef<Path>, map: Option<&[usize]>) -> Result<Reference> {
+    let fp = File::open(path).with_context(|| anyhow!("ref file not found"))?;
+    let mut npz = NpzReader::new(fp).with_context(|| anyhow!("cannot open ref npz"))?;
+    let data = read_layout(&mut npz, map)?;
+    let unfilled = read_int(&mut npz, "unfilled").or_else(|| read_int(&mut npz, "unfilled.npy"));
+    let id: Option<Array2<i64>> = read_int(&mut npz, "id").or_else(|| read_int(&mut npz, "id.npy"));
+    check_shape("unfilled", unfilled.as_ref(), data.shape())?;
+    check_shape("id", id.as_ref(), data.shape())?;
+    if let Some(v) = id.iter().flatten().find(|v| u32::try_from(**v).is_err()) {
+        bail!("id has invalid value {}", v);
+    }
+    Ok(Reference {
+        data,
+        unfilled: unfilled.map(|a| a.mapv(|v| v != 0)),
+        id: id.map(|a| a.mapv(|v| v as u32)),
+    })
+}
+
+fn read_layout(npz: &mut NpzReader<File>, map: Option<&[usize]>) -> Result<Array2<u8>> {
+    if let Some(label) = read_int(npz, "label").or_else(|| read_int(npz, "label.npy")) {
+        if let Some(map) = map {
+            check_shape("label", Some(&label), map)?;
+        }
+        let (raw, invalid) = img::from_label(&label);
+        for (id, reason, cells) in invalid {
+            match reason {
+                img::Invalid::Shape => log!("ref label {} is not a tetromino: {:?}", id, cells),
+                img::Invalid::Collision => {
+                    log!("ref label {} collides with another piece: {:?}", id, cells)
+                }
+            }
+        }
+        return Ok(raw);
+    }
+    let raw: Array2<u8> = (npz.by_name("piece"))
+        .or_else(|_| npz.by_name("piece.npy"))
+        .with_context(|| anyhow!("piece or label var not found"))?;
+    if let Some(map) = map {
+        check_shape("piece", Some(&raw), map)?;
+    }
+    if let Some(v) = raw.iter().find(|v| **v as usize >= TETROMINO.len()) {
+        bail!("piece has invalid value {}", v);
+    }
+    Ok(raw)
+}
+
+/// Read integer array of any dtype
+fn read_int<D: Dimension>(npz: &mut NpzReader<File>, name: &str) -> Option<Array<i64, D>> {
+    macro_rules! try_read {
+        ($($t:ty),*) => {$(
+            if let Ok(a) = npz.by_name::<OwnedRepr<$t>, D>(name) {
+                return Some(a.mapv(|v| v as i64));
+            }
+        )*};
+    }
+    try_read!(i64, u8, u16, u32, u64, i8, i16, i32);
+    None
+}
+
+/// Read number array of any dtype as float
+fn read_float<D: Dimension>(npz: &mut NpzReader<File>, name: &str) -> Option<Array<f64, D>> {
+    if let Ok(a) = npz.by_name::<OwnedRepr<f64>, D>(name) {
+        return Some(a);
+    }
+    if let Ok(a) = npz.by_name::<OwnedRepr<f32>, D>(name) {
+        return Some(a.mapv(|v| v as f64));
+    }
+    read_int(npz, name).map(|a| a.mapv(|v| v as f64))
+}
+
+/// Write solver input `map` (255 for filled, like preprocess.py) and `gray`
+pub fn write_input(path: impl AsRef<Path>, map: &Array2<bool>, gray: &Array2<u8>) -> Result<()> {
+    let fp = File::create(path).with_context(|| "Cannot create output file")?;
+    let mut npz = NpzWriter::new(fp);
+    (|| {
+        npz.add_array("map", &map.mapv(|v| v as u8 * 255))?;
+        npz.add_array("gray", gray)?;
+        npz.finish()?;
+        Ok::<_, ndarray_npy::WriteNpzError>(())
+    })()
+    .with_context(|| "Cannot write output file")
+}
+
+/// Write selected arrays of the layout `data` over `map`, with persistent piece `id`
+pub fn write_output(
+    path: impl AsRef<Path>,
+    map: &Array2<bool>,
+    data: &Array2<u8>,
+    id: &Array2<u32>,
+    arrays: &[OutputArray],
+) -> Result<()> {
+    let fp = File::create(path).with_context(|| "Cannot create output file")?;
+    let mut npz = NpzWriter::new(fp);
+    let (label, kind) = img::label(data);
+    for array in arrays {
+        match array {
+            OutputArray::Piece => npz.add_array("piece", data),
+            OutputArray::Label => npz.add_array("label", &label),
+            OutputArray::Type => npz.add_array("type", &kind),
+            OutputArray::Unfilled => npz.add_array(
+                "unfilled",
+                &(map & &img::lay(data).mapv(|v| !v)).mapv(|v| v as u8),
+            ),
+            OutputArray::Id => npz.add_array("id", id),
+        }
+        .with_context(|| "Cannot write output file")?;
+    }
+    npz.finish().with_context(|| "Cannot write output file")?;
+    Ok(())
+}
+
+/// Write one row per piece: `id`, `event`, `piece`, `from` and `to` centers, and `turn`
+pub fn write_keys(path: impl AsRef<Path>, keys: &[tween::Key]) -> Result<()> {
+    let fp = File::create(path).with_context(|| "Cannot create output file")?;
+    let mut npz = NpzWriter::new(fp);
+    let center = |f: fn(&tween::Key) -> (f64, f64)| {
+        let flat: Vec<_> = keys.iter().flat_map(|k| <[f64; 2]>::from(f(k))).collect();
+        Array2::from_shape_vec((keys.len(), 2), flat).unwrap()
+    };
+    (|| {
+        npz.add_array("id", &keys.iter().map(|k| k.id).collect::<Array1<_>>())?;
+        npz.add_array(
+            "event",
+            &keys.iter().map(|k| k.event as u8).collect::<Array1<_>>(),
+        )?;
+        npz.add_array(
+            "piece",
+            &keys.iter().map(|k| k.piece).collect::<Array1<_>>(),
+        )?;
+        npz.add_array("from", &center(|k| k.from))?;
+        npz.add_array("to", &center(|k| k.to))?;
+        npz.add_array("turn", &keys.iter().map(|k| k.turn).collect::<Array1<_>>())?;
+        npz.finish()?;
+        Ok::<_, ndarray_npy::WriteNpzError>(())
+    })()
+    .with_context(|| "Cannot write output file")
+}
+
+/// One solved frame of a stacked sequence
+pub struct Solved {
+    pub data: Array2<u8>,
+    pub id: Array2<u32>,
+    pub seconds: f64,
+}
+
+/// Write selected arrays stacked as 3d (frames × h × w), and per frame `frame_pieces`,
+/// `frame_unfilled` (cell count) and `frame_seconds`
+pub fn write_stack(
+    path: impl AsRef<Path>,
+    inputs: &[solve::Input],
+    solved: &[Solved],
+    arrays: &[OutputArray],
+) -> Result<()> {
+    if solved.is_empty() {
+        bail!("no frames to write");
+    }
+    fn stack<T: Clone>(frames: Vec<Array2<T>>) -> Result<Array3<T>> {
+        let views: Vec<_> = frames.iter().map(|a| a.view()).collect();
+        ndarray::stack(Axis(0), &views).with_context(|| "frames differ in shape")
+    }
+    let unfilled: Vec<Array2<u8>> = inputs
+        .iter()
+        .zip(solved)
+        .map(|(i, s)| (&i.map & &img::lay(&s.data).mapv(|v| !v)).mapv(|v| v as u8))
+        .collect();
+    let fp = File::create(path).with_context(|| "Cannot create output file")?;
+    let mut npz = NpzWriter::new(fp);
+    (|| {
+        for array in arrays {
+            match array {
+                OutputArray::Piece => npz.add_array(
+                    "piece",
+                    &stack(solved.iter().map(|s| s.data.clone()).collect())?,
+                ),
+                OutputArray::Label => npz.add_array(
+                    "label",
+                    &stack(solved.iter().map(|s| img::label(&s.data).0).collect())?,
+                ),
+                OutputArray::Type => npz.add_array(
+                    "type",
+                    &stack(solved.iter().map(|s| img::label(&s.data).1).collect())?,
+                ),
+                OutputArray::Unfilled => npz.add_array("unfilled", &stack(unfilled.clone())?),
+                OutputArray::Id => {
+                    npz.add_array("id", &stack(solved.iter().map(|s| s.id.clone()).collect())?)
+                }
+            }?;
+        }
+        let pieces: Array1<u32> = solved
+            .iter()
+            .map(|s| s.data.iter().filter(|v| **v != 0).count() as u32)
+            .collect();
+        npz.add_array("frame_pieces", &pieces)?;
+        let count: Array1<u32> = unfilled
+            .iter()
+            .map(|u| u.iter().map(|v| *v as u32).sum())
+            .collect();
+        npz.add_array("frame_unfilled", &count)?;
+        let seconds: Array1<f64> = solved.iter().map(|s| s.seconds).collect();
+        npz.add_array("frame_seconds", &seconds)?;
+        npz.finish()?;
+        Ok::<_, anyhow::Error>(())
+    })()
+    .with_context(|| "Cannot write output file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `arrays` to a scratch .npz and `read` it back
+    fn read_back<T>(
+        name: &str,
+        arrays: &[(&str, ArrayD<u8>)],
+        read: impl FnOnce(&Path) -> Result<T>,
+    ) -> Result<T> {
+        let path = std::env::temp_dir().join(format!("tetris_{}_{}.npz", name, std::process::id()));
+        let mut npz = NpzWriter::new(File::create(&path)?);
+        for (name, a) in arrays {
+            npz.add_array(*name, a)?;
+        }
+        npz.finish()?;
+        let out = read(&path);
+        std::fs::remove_file(&path)?;
+        out
+    }
+
+    #[test]
+    fn test_read_stack() {
+        let stack = |name, arrays: &[_]| read_back(name, arrays, |p| read_stack(p));
+        let map = Array3::<u8>::ones((2, 3, 4)).into_dyn();
+        let inputs = stack("ok", &[("map", map.clone())]).unwrap().unwrap();
+        assert_eq!(inputs.len(), 2);
+        assert!(stack(
+            "none",
+            &[("map", Array3::<u8>::zeros((0, 3, 4)).into_dyn())]
+        )
+        .is_err());
+        let gray = Array3::<u8>::zeros((1, 3, 4)).into_dyn();
+        assert!(stack("frames", &[("map", map.clone()), ("gray", gray)]).is_err());
+        let lock = Array3::<u8>::zeros((2, 4, 3)).into_dyn();
+        assert!(stack("size", &[("map", map), ("lock", lock)]).is_err());
+    }
+
+    #[test]
+    fn test_read_ref() {
+        let layout = |name, piece: Array2<u8>| {
+            read_back(name, &[("piece", piece.into_dyn())], |p| {
+                read_ref(p, Some(&[2, 4])).map(|r| r.data)
+            })
+        };
+        let piece = array![[2, 0, 0, 0], [0, 0, 0, 0]];
+        assert_eq!(layout("ref", piece.clone()).unwrap(), piece);
+        assert!(layout("ref_shape", Array2::zeros((4, 2))).is_err());
+        assert!(layout("ref_value", array![[20, 0, 0, 0], [0, 0, 0, 0]]).is_err());
+
+        let ids = |name, id: ArrayD<u8>| {
+            let piece = piece.clone().into_dyn();
+            read_back(name, &[("piece", piece), ("id", id)], |p| read_ref(p, None))
+        };
+        let id = array![[1, 1, 1, 1], [0, 0, 0, 0]];
+        assert_eq!(
+            ids("id", id.clone().into_dyn()).unwrap().id,
+            Some(id.mapv(|v| v as u32))
+        );
+        assert!(ids("id_shape", Array2::zeros((4, 2)).into_dyn()).is_err());
+        let unfilled = Array2::<u8>::zeros((4, 2)).into_dyn();
+        let piece = piece.into_dyn();
+        assert!(read_back(
+            "unfilled_shape",
+            &[("piece", piece), ("unfilled", unfilled)],
+            |p| read_ref(p, None)
+        )
+        .is_err());
+    }
+}
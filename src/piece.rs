@@ -127,3 +127,60 @@ pub const TETROMINO: &[[(u8, u8); 4]; 20] = &[
         (2, 1),
     ],
 ];
+
+/// Cells covered by piece `v` anchored at `(y, x)`, `None` if out of bound
+pub fn cells(v: u8, (y, x): (usize, usize), (h, w): (usize, usize)) -> Option<[(usize, usize); 4]> {
+    let mut out = [(0, 0); 4];
+    for (o, (dy, dx)) in out.iter_mut().zip(TETROMINO[v as usize]) {
+        let (y, x) = (y + dy as usize, x + dx as usize);
+        if y >= h || x >= w {
+            return None;
+        }
+        *o = (y, x);
+    }
+    Some(out)
+}
+
+/// Piece kind regardless of rotation: 1 = O, 2 = I, 3 = J, 4 = L, 5 = S, 6 = Z, 7 = T
+pub fn kind(v: u8) -> u8 {
+    match v {
+        0 => 0,
+        1 => 1,
+        2..=3 => 2,
+        4..=7 => 3,
+        8..=11 => 4,
+        12..=13 => 5,
+        14..=15 => 6,
+        _ => 7,
+    }
+}
+
+/// Piece `v` turned a quarter clockwise
+pub fn rotate(v: u8) -> u8 {
+    if v == 0 {
+        return 0;
+    }
+    let normal = |cells: [(i32, i32); 4]| {
+        let my = cells.iter().map(|c| c.0).min().unwrap();
+        let mx = cells.iter().map(|c| c.1).min().unwrap();
+        let mut out = cells.map(|(y, x)| (y - my, x - mx));
+        out.sort();
+        out
+    };
+    let turned = normal(TETROMINO[v as usize].map(|(y, x)| (x as i32, -(y as i32))));
+    (1..TETROMINO.len())
+        .find(|&i| normal(TETROMINO[i].map(|(y, x)| (y as i32, x as i32))) == turned)
+        .unwrap() as u8
+}
+
+/// Quarter turns clockwise from piece `a` to `b`, `None` if they are different kinds
+pub fn turns(a: u8, b: u8) -> Option<u8> {
+    let mut v = a;
+    for turn in 0..4 {
+        if v == b {
+            return Some(turn);
+        }
+        v = rotate(v);
+    }
+    None
+}
@@ -0,0 +1,428 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use ndarray::{prelude::*, Zip};
+
+use crate::{batch, npz};
+
+/// How gray levels are turned into filled cells
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    /// Brighter than `--threshold`
+    Global,
+    /// Level chosen per frame from its histogram
+    Otsu,
+    /// Brighter than the blurred neighbourhood, like preprocess.py -a
+    Adaptive,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+#[command(next_help_heading = "Preprocessing")]
+pub struct Options {
+    /// Grid size in cells, WIDTHxHEIGHT. Default keeps the frame size
+    #[arg(long, value_parser = parse_size)]
+    pub grid: Option<(usize, usize)>,
+    #[arg(long, value_enum, default_value_t = Method::Global)]
+    pub method: Method,
+    /// Gray level above which a cell is filled, for the global method
+    #[arg(long, default_value_t = 127)]
+    pub threshold: u8,
+    /// Fill dark cells instead, gray is inverted before thresholding
+    #[arg(long)]
+    pub invert: bool,
+    /// Gray levels past the threshold that change a cell's state from the last frame at once
+    #[arg(long, default_value_t = 0)]
+    pub margin: u8,
+    /// Frames in a row a cell must be on the other side of the threshold to change state
+    /// when it stays within the margin
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub debounce: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            grid: None,
+            method: Method::Global,
+            threshold: 127,
+            invert: false,
+            margin: 0,
+            debounce: 1,
+        }
+    }
+}
+
+/// Parse WIDTHxHEIGHT
+pub fn parse_size(s: &str) -> Result<(usize, usize)> {
+    let (w, h) = s.split_once('x').context("expected WIDTHxHEIGHT")?;
+    let (w, h) = (w.parse()?, h.parse()?);
+    if w == 0 || h == 0 {
+        bail!("size must not be empty");
+    }
+    Ok((w, h))
+}
+
+/// Source pixels and their share of each of `out` cells along an axis of `len` pixels
+fn area_weights(len: usize, out: usize) -> Vec<Vec<(usize, f64)>> {
+    let step = len as f64 / out as f64;
+    (0..out)
+        .map(|i| {
+            let (start, end) = (i as f64 * step, (i + 1) as f64 * step);
+            let mut w = vec![];
+            let mut p = start.floor() as usize;
+            while (p as f64) < end && p < len {
+                let share = (end.min(p as f64 + 1.0) - start.max(p as f64)) / step;
+                if share > 0.0 {
+                    w.push((p, share));
+                }
+                p += 1;
+            }
+            w
+        })
+        .collect()
+}
+
+/// Resize to `(h, w)` averaging the area each cell covers
+pub fn resize(gray: &Array2<u8>, (h, w): (usize, usize)) -> Array2<u8> {
+    if gray.dim() == (h, w) {
+        return gray.clone();
+    }
+    let ys = area_weights(gray.nrows(), h);
+    let xs = area_weights(gray.ncols(), w);
+    Array2::from_shape_fn((h, w), |(y, x)| {
+        let mut sum = 0.0;
+        for &(sy, wy) in &ys[y] {
+            for &(sx, wx) in &xs[x] {
+                sum += gray[(sy, sx)] as f64 * wy * wx;
+            }
+        }
+        sum.round().clamp(0.0, 255.0) as u8
+    })
+}
+
+/// Level maximizing the variance between the two classes of the histogram
+pub fn otsu(gray: &Array2<u8>) -> u8 {
+    let mut hist = [0u64; 256];
+    for &v in gray {
+        hist[v as usize] += 1;
+    }
+    let total = gray.len() as f64;
+    let sum: f64 = hist
+        .iter()
+        .enumerate()
+        .map(|(v, n)| v as f64 * *n as f64)
+        .sum();
+    let (mut below, mut below_sum) = (0.0, 0.0);
+    let (mut best, mut best_var) = (0, -1.0);
+    for (t, &n) in hist.iter().enumerate() {
+        below += n as f64;
+        below_sum += t as f64 * n as f64;
+        let above = total - below;
+        if below == 0.0 || above == 0.0 {
+            continue;
+        }
+        let diff = below_sum / below - (sum - below_sum) / above;
+        let var = below * above * diff * diff;
+        if var > best_var {
+            (best, best_var) = (t, var);
+        }
+    }
+    best as u8
+}
+
+/// Kernel size of the adaptive method's Gaussian blur, as in preprocess.py
+const ADAPTIVE_SIZE: usize = 13;
+
+/// Separable Gaussian blur with mirrored border, sigma chosen from the kernel size
+/// like OpenCV does
+fn blur(gray: &Array2<f64>, size: usize) -> Array2<f64> {
+    let r = size / 2;
+    let sigma = 0.3 * ((size as f64 - 1.0) * 0.5 - 1.0) + 0.8;
+    let kernel: Vec<f64> = (0..size)
+        .map(|i| (-((i as f64 - r as f64).powi(2)) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let norm: f64 = kernel.iter().sum();
+    let mirror = |i: isize, len: usize| -> usize {
+        let len = len as isize;
+        if len == 1 {
+            return 0;
+        }
+        let period = 2 * (len - 1);
+        let i = i.rem_euclid(period);
+        (if i < len { i } else { period - i }) as usize
+    };
+    let pass = |a: &Array2<f64>, axis: usize| {
+        let len = a.shape()[axis];
+        Array2::from_shape_fn(a.dim(), |(y, x)| {
+            let mut sum = 0.0;
+            for (k, w) in kernel.iter().enumerate() {
+                let d = k as isize - r as isize;
+                let v = if axis == 0 {
+                    a[(mirror(y as isize + d, len), x)]
+                } else {
+                    a[(y, mirror(x as isize + d, len))]
+                };
+                sum += v * w;
+            }
+            sum / norm
+        })
+    };
+    pass(&pass(gray, 1), 0)
+}
+
+/// Gray level of each cell above which it is filled
+fn level(gray: &Array2<u8>, opts: &Options) -> Array2<f64> {
+    match opts.method {
+        Method::Global => Array2::from_elem(gray.dim(), opts.threshold as f64),
+        Method::Otsu => Array2::from_elem(gray.dim(), otsu(gray) as f64),
+        Method::Adaptive => {
+            let local = blur(&gray.mapv(|v| v as f64), ADAPTIVE_SIZE);
+            local.mapv(|v| (v - 127.5) * 0.9 + 127.5)
+        }
+    }
+}
+
+/// Cells of `gray` brighter than their level
+fn threshold(gray: &Array2<u8>, level: &Array2<f64>) -> Array2<bool> {
+    Zip::from(gray)
+        .and(level)
+        .map_collect(|&g, &l| g as f64 > l)
+}
+
+/// Cell state carried from frame to frame, so that noise around the threshold
+/// does not make cells flicker
+#[derive(Default)]
+pub struct Hysteresis {
+    map: Option<Array2<bool>>,
+    /// Frames in a row each cell has wanted to change state
+    pending: Array2<u32>,
+}
+
+impl Hysteresis {
+    /// Filled cells of `gray`, changing state from the last frame where the gray level is at
+    /// least `margin` past the threshold, or has been past it for `debounce` frames in a row
+    pub fn apply(&mut self, gray: &Array2<u8>, opts: &Options) -> Array2<bool> {
+        let level = level(gray, opts);
+        let raw = threshold(gray, &level);
+        let Some(prev) = self.map.as_ref().filter(|p| p.dim() == raw.dim()) else {
+            self.pending = Array2::zeros(raw.dim());
+            self.map = Some(raw.clone());
+            return raw;
+        };
+        let mut map = prev.clone();
+        Zip::from(&mut map)
+            .and(&mut self.pending)
+            .and(&raw)
+            .and(gray)
+            .and(&level)
+            .for_each(|m, pending, &r, &g, &l| {
+                if r == *m {
+                    *pending = 0;
+                    return;
+                }
+                *pending += 1;
+                if *pending >= opts.debounce || (g as f64 - l).abs() >= opts.margin as f64 {
+                    *m = r;
+                    *pending = 0;
+                }
+            });
+        self.map = Some(map.clone());
+        map
+    }
+}
+
+/// Grid-sized gray and the map thresholded from it, against the state of the last frame
+pub fn frame(
+    gray: &Array2<u8>,
+    opts: &Options,
+    state: &mut Hysteresis,
+) -> (Array2<bool>, Array2<u8>) {
+    let mut gray = match opts.grid {
+        Some((w, h)) => resize(gray, (h, w)),
+        None => gray.clone(),
+    };
+    if opts.invert {
+        gray.mapv_inplace(|v| 255 - v);
+    }
+    (state.apply(&gray, opts), gray)
+}
+
+/// Threshold frames of `files` into numbered inputs in `dir`, starting at #`from`
+pub fn run(
+    dir: &Path,
+    files: &[PathBuf],
+    from: usize,
+    size: Option<(usize, usize)>,
+    opts: &Options,
+) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| "Cannot create working directory")?;
+    let mut index = from;
+    let mut state = Hysteresis::default();
+    for file in files {
+        for gray in read_frames(file, size)? {
+            let (map, gray) = frame(&gray, opts, &mut state);
+            npz::write_input(batch::frame_path(dir, index).0, &map, &gray)?;
+            index += 1;
+        }
+    }
+    log!("preprocess: {} frames", index - from);
+    Ok(())
+}
+
+/// Read 8-bit binary PGM (P5) images, several may be concatenated
+fn read_pgm(bytes: &[u8]) -> Result<Vec<Array2<u8>>> {
+    let mut frames = vec![];
+    let mut pos = 0;
+    // next whitespace separated header token, skipping comments
+    let token = |pos: &mut usize| -> Result<String> {
+        loop {
+            match bytes.get(*pos) {
+                Some(b'#') => {
+                    while bytes.get(*pos).is_some_and(|c| *c != b'\n') {
+                        *pos += 1;
+                    }
+                }
+                Some(c) if c.is_ascii_whitespace() => *pos += 1,
+                Some(_) => break,
+                None => bail!("truncated PGM header"),
+            }
+        }
+        let start = *pos;
+        while bytes.get(*pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+            *pos += 1;
+        }
+        Ok(String::from_utf8_lossy(&bytes[start..*pos]).into_owned())
+    };
+    while bytes[pos..].iter().any(|c| !c.is_ascii_whitespace()) {
+        if token(&mut pos)? != "P5" {
+            bail!("only binary PGM (P5) is supported");
+        }
+        let w: usize = token(&mut pos)?.parse().context("bad PGM width")?;
+        let h: usize = token(&mut pos)?.parse().context("bad PGM height")?;
+        let max: usize = token(&mut pos)?.parse().context("bad PGM maxval")?;
+        if max == 0 || max > 255 {
+            bail!("only 8-bit PGM is supported");
+        }
+        // single whitespace before the raster
+        match bytes.get(pos) {
+            Some(c) if c.is_ascii_whitespace() => pos += 1,
+            Some(_) => bail!("malformed PGM header"),
+            None => bail!("truncated PGM header"),
+        }
+        let raster = bytes
+            .get(pos..pos + w * h)
+            .context("truncated PGM raster")?;
+        pos += w * h;
+        let frame = Array2::from_shape_vec((h, w), raster.to_vec()).unwrap();
+        frames.push(frame.mapv(|v| (v as usize * 255 / max) as u8));
+    }
+    Ok(frames)
+}
+
+/// Read grayscale frames from `.pgm`, `.npz` (`gray` array, 2d or stacked 3d) or raw 8-bit
+/// frames of `size` (WIDTHxHEIGHT), as written by `ffmpeg -pix_fmt gray -f rawvideo`
+pub fn read_frames(path: &Path, size: Option<(usize, usize)>) -> Result<Vec<Array2<u8>>> {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    if ext.eq_ignore_ascii_case("npz") {
+        return npz::read_gray(path);
+    }
+    let bytes = std::fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    if ext.eq_ignore_ascii_case("pgm") {
+        return read_pgm(&bytes);
+    }
+    let (w, h) = size.context("raw input needs --size")?;
+    if bytes.len() % (w * h) != 0 {
+        bail!("raw input is not a whole number of {}x{} frames", w, h);
+    }
+    Ok(bytes
+        .chunks(w * h)
+        .map(|c| Array2::from_shape_vec((h, w), c.to_vec()).unwrap())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preprocess() {
+        assert_eq!(parse_size("48x36").unwrap(), (48, 36));
+        assert!(parse_size("4x0").is_err());
+        // 2x2 blocks average to one cell
+        let gray = array![
+            [0, 0, 200, 255], //
+            [0, 100, 255, 255],
+        ];
+        assert_eq!(resize(&gray, (1, 2)), array![[25, 241]]);
+        // 3 pixels into 2 cells, the middle one is split
+        assert_eq!(resize(&array![[0, 90, 180]], (1, 2)), array![[30, 150]]);
+
+        let opts = Options {
+            grid: Some((2, 1)),
+            ..Default::default()
+        };
+        let (map, small) = frame(&gray, &opts, &mut Hysteresis::default());
+        assert_eq!(map, array![[false, true]]);
+        assert_eq!(small, array![[25, 241]]);
+        let (map, _) = frame(
+            &gray,
+            &Options {
+                invert: true,
+                ..opts.clone()
+            },
+            &mut Hysteresis::default(),
+        );
+        assert_eq!(map, array![[true, false]]);
+
+        let dark = array![[10, 20, 30, 60, 70, 80]];
+        assert!((30..60).contains(&otsu(&dark)));
+        let opts = Options {
+            method: Method::Otsu,
+            threshold: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            threshold(&dark, &level(&dark, &opts)),
+            array![[false, false, false, true, true, true]]
+        );
+        let flat = |v: u8| Array2::from_elem((5, 5), v);
+        // uniform frame is filled if bright, with a bias like preprocess.py
+        let opts = Options {
+            method: Method::Adaptive,
+            ..Default::default()
+        };
+        assert!(threshold(&flat(200), &level(&flat(200), &opts))
+            .iter()
+            .all(|v| *v));
+        assert!(!threshold(&flat(40), &level(&flat(40), &opts))
+            .iter()
+            .any(|v| *v));
+        let mut spot = Array2::from_elem((5, 5), 200u8);
+        spot[(2, 2)] = 0;
+        let map = threshold(&spot, &level(&spot, &opts));
+        assert!(!map[(2, 2)] && map[(0, 0)]);
+
+        // a cell near the threshold changes after two frames, one past the margin at once
+        let opts = Options {
+            margin: 20,
+            debounce: 2,
+            ..Default::default()
+        };
+        let mut state = Hysteresis::default();
+        let mut run = |g: [u8; 2]| state.apply(&array![g], &opts).into_raw_vec();
+        assert_eq!(run([200, 100]), vec![true, false]);
+        assert_eq!(run([120, 140]), vec![true, false]);
+        assert_eq!(run([120, 140]), vec![false, true]);
+        assert_eq!(run([200, 100]), vec![true, false]);
+        // going back resets the count
+        assert_eq!(run([120, 140]), vec![true, false]);
+        assert_eq!(run([200, 100]), vec![true, false]);
+        assert_eq!(run([120, 140]), vec![true, false]);
+
+        let pgm = b"P5\n# comment\n3 1\n255\n\x00\x80\xffP5 1 1 15 \x0f";
+        let frames = read_pgm(pgm).unwrap();
+        assert_eq!(frames, vec![array![[0, 128, 255]], array![[255]]]);
+        assert!(read_pgm(b"P5 1 1 255").is_err());
+    }
+}
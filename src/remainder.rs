@@ -0,0 +1,182 @@
+use crate::check;
+use crate::img::{self, Segment};
+use ndarray::{Array2, ArrayView2};
+
+/// Number of cheapest cells to try combinations of
+const CANDIDATE: usize = 8;
+
+/// Hints for picking cells to leave empty, in segment coordinates
+#[derive(Default, Clone, Copy)]
+pub struct Hint<'a> {
+    /// Source brightness, darker cells are dropped first
+    pub gray: Option<ArrayView2<'a, u8>>,
+    /// Cells that were left empty in previous frame
+    pub prev_empty: Option<ArrayView2<'a, bool>>,
+    /// Fitness for covering each cell, lower weight cells are dropped first
+    pub weight: Option<ArrayView2<'a, i32>>,
+}
+
+/// Remove `map_size % 4` cells from segment, keeping it connected
+pub fn trim_remainder(seg: &Segment, hint: Hint) -> Array2<bool> {
+    let need = seg.map_size % 4;
+    if need == 0 {
+        return seg.map.clone();
+    }
+    let map = &seg.map;
+
+    let mut cells: Vec<_> = map
+        .indexed_iter()
+        .filter(|(_, v)| **v)
+        .map(|(pos, _)| (cost(map, pos, &hint), pos))
+        .collect();
+    cells.sort();
+    let candidate: Vec<_> = cells
+        .into_iter()
+        .filter(|(_, pos)| {
+            let mut map = map.clone();
+            map[*pos] = false;
+            img::segment(&map).len() == 1
+        })
+        .take(CANDIDATE)
+        .collect();
+
+    // cheapest combination that passes the infeasibility check,
+    // or just the cheapest connected one
+    let mut combo: Vec<(i32, Vec<usize>)> = combinations(candidate.len(), need)
+        .into_iter()
+        .map(|c| (c.iter().map(|i| candidate[*i].0).sum(), c))
+        .collect();
+    combo.sort();
+    let mut fallback = None;
+    for (_, c) in combo {
+        let mut map = map.clone();
+        for i in c {
+            map[candidate[i].1] = false;
+        }
+        if need > 1 && img::segment(&map).len() != 1 {
+            continue;
+        }
+        if check::check(&map).is_ok() {
+            return map;
+        }
+        fallback.get_or_insert(map);
+    }
+    fallback.unwrap_or_else(|| scan(seg))
+}
+
+/// Lower is removed first
+fn cost(map: &Array2<bool>, (y, x): (usize, usize), hint: &Hint) -> i32 {
+    let &[h, w] = map.shape() else { unreachable!() };
+    let neighbour = (y > 0 && map[(y - 1, x)]) as i32
+        + (y < h - 1 && map[(y + 1, x)]) as i32
+        + (x > 0 && map[(y, x - 1)]) as i32
+        + (x < w - 1 && map[(y, x + 1)]) as i32;
+    // protruding cells first
+    let mut cost = 16 * neighbour;
+    if let Some(gray) = hint.gray {
+        cost += gray[(y, x)] as i32 / 8;
+    }
+    if let Some(weight) = hint.weight {
+        cost += 16 * weight[(y, x)];
+    }
+    if let Some(prev) = hint.prev_empty {
+        if prev[(y, x)] {
+            cost -= 32;
+        }
+    }
+    cost
+}
+
+fn combinations(n: usize, k: usize) -> Vec<Vec<usize>> {
+    if k == 0 {
+        return vec![vec![]];
+    }
+    let mut out = vec![];
+    for last in (k - 1)..n {
+        for mut c in combinations(last, k - 1) {
+            c.push(last);
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Remove the first cells in scan order that keep the segment connected
+fn scan(seg: &Segment) -> Array2<bool> {
+    let &[h, w] = seg.map.shape() else {
+        unreachable!()
+    };
+    let mut map = seg.map.clone();
+    let mut need_remove = seg.map_size % 4;
+    'outer: for y in 0..h {
+        for x in 0..w {
+            if map[(y, x)] {
+                map[(y, x)] = false;
+                if img::segment(&map).len() != 1 {
+                    map[(y, x)] = true;
+                    continue;
+                }
+                need_remove -= 1;
+                if need_remove == 0 {
+                    break 'outer;
+                }
+            }
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    fn seg(map: Array2<bool>) -> Segment {
+        Segment {
+            map_size: map.iter().filter(|v| **v).count(),
+            map,
+            x: 0,
+            y: 0,
+        }
+    }
+
+    #[test]
+    fn test_spur() {
+        let map = array![
+            [1, 1, 1, 1, 0], //
+            [1, 1, 1, 1, 1],
+        ]
+        .mapv(|x| x != 0);
+        let out = trim_remainder(&seg(map), Hint::default());
+        assert!(!out[(1, 4)]);
+        assert_eq!(out.iter().filter(|v| **v).count(), 8);
+    }
+    #[test]
+    fn test_prev_empty() {
+        let map = array![
+            [1, 1, 1, 1, 1], //
+            [1, 1, 1, 1, 0],
+        ]
+        .mapv(|x| x != 0);
+        let prev = array![
+            [false, false, false, false, false], //
+            [true, false, false, false, false],
+        ];
+        let hint = Hint {
+            prev_empty: Some(prev.view()),
+            ..Default::default()
+        };
+        let out = trim_remainder(&seg(map.clone()), hint);
+        assert!(!out[(1, 0)]);
+
+        // low weight wins over both
+        let mut weight = Array2::from_elem((2, 5), 4);
+        weight[(0, 2)] = 0;
+        let hint = Hint {
+            weight: Some(weight.view()),
+            ..hint
+        };
+        let out = trim_remainder(&seg(map), hint);
+        assert!(!out[(0, 2)]);
+    }
+}
@@ -0,0 +1,121 @@
+use ndarray::Array2;
+
+use crate::{img, piece};
+
+/// Block look, same as render.py
+#[derive(clap::Args, Debug, Clone)]
+pub struct Style {
+    /// Block size in pixel
+    #[arg(long, default_value_t = 24)]
+    pub scale: usize,
+    /// Stroke width in pixel
+    #[arg(long, default_value_t = 2)]
+    pub stroke: usize,
+    /// Paint unfilled blocks
+    #[arg(long)]
+    pub unfilled: bool,
+}
+
+const STROKE: [u8; 3] = [80, 80, 80];
+
+/// Color of each piece kind, 0 for unfilled
+fn color(kind: u8) -> [u8; 3] {
+    const HUE: [f64; 8] = [0.0, 182.0, 59.0, 231.0, 34.0, 113.0, 0.0, 302.0];
+    if kind == 0 {
+        return hsv(0.0, 0.0, 0.70);
+    }
+    hsv(HUE[kind as usize], 0.20, 0.97)
+}
+
+fn hsv(h: f64, s: f64, v: f64) -> [u8; 3] {
+    let c = v * s;
+    let h = h / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    [r, g, b].map(|i| ((i + m) * 255.0) as u8)
+}
+
+/// Draw layout as rgb24 pixels, `scale` pixels per cell. Stroke is drawn only where
+/// the neighbouring cell belongs to another piece.
+pub fn render(map: &Array2<bool>, data: &Array2<u8>, style: &Style) -> Vec<u8> {
+    let &[h, w] = data.shape() else {
+        unreachable!()
+    };
+    let (scale, stroke) = (style.scale, style.stroke);
+    let (label, kind) = img::label(data);
+    let mut out = vec![0; h * scale * w * scale * 3];
+    for ((y, x), &l) in label.indexed_iter() {
+        if l == 0 && !(style.unfilled && map[(y, x)]) {
+            continue;
+        }
+        let fill = color(piece::kind(kind[(y, x)]));
+        let same = |dy: isize, dx: isize| {
+            let (Some(ny), Some(nx)) = (y.checked_add_signed(dy), x.checked_add_signed(dx)) else {
+                return false;
+            };
+            l != 0 && ny < h && nx < w && label[(ny, nx)] == l
+        };
+        for py in 0..scale {
+            let dy = if py < stroke {
+                -1
+            } else if py >= scale - stroke {
+                1
+            } else {
+                0
+            };
+            for px in 0..scale {
+                let dx = if px < stroke {
+                    -1
+                } else if px >= scale - stroke {
+                    1
+                } else {
+                    0
+                };
+                let is_stroke = l != 0
+                    && match (dy, dx) {
+                        (0, 0) => false,
+                        (dy, 0) => !same(dy, 0),
+                        (0, dx) => !same(0, dx),
+                        (dy, dx) => !(same(dy, 0) && same(0, dx) && same(dy, dx)),
+                    };
+                let i = ((y * scale + py) * w * scale + x * scale + px) * 3;
+                out[i..(i + 3)].copy_from_slice(if is_stroke { &STROKE } else { &fill });
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    #[test]
+    fn test_render() {
+        let data = array![[2, 0, 0, 0]];
+        let map = Array2::from_elem((1, 4), true);
+        let style = Style {
+            scale: 4,
+            stroke: 1,
+            unfilled: false,
+        };
+        let out = render(&map, &data, &style);
+        assert_eq!(out.len(), 4 * 16 * 3);
+        let px = |y: usize, x: usize| &out[(y * 16 + x) * 3..(y * 16 + x) * 3 + 3];
+        // outline all around, but not between cells of the same piece
+        assert_eq!(px(0, 5), STROKE);
+        assert_eq!(px(1, 0), STROKE);
+        assert_eq!(px(1, 3), color(2));
+        assert_eq!(px(1, 4), color(2));
+        assert_eq!(color(2), [247, 246, 197]);
+    }
+}
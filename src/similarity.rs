@@ -0,0 +1,136 @@
+use crate::img;
+use crate::piece;
+use clap::ValueEnum;
+use ndarray::{Array2, ArrayView2};
+use std::cmp::max;
+
+/// Points for a piece that is kept exactly as in reference
+pub const PIECE: i32 = 4;
+
+/// How to measure similarity to reference layout
+#[derive(ValueEnum, Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum Metric {
+    /// Same piece at the same anchor
+    #[default]
+    Exact,
+    /// Cell IoU with the most overlapping reference piece
+    Iou,
+    /// Overlapping cells of the same piece kind
+    Type,
+    /// Same piece at the same anchor, or shifted by one cell for half points
+    Shift,
+}
+
+/// Reference layout with its per-cell labels
+#[derive(Debug)]
+pub struct Reference {
+    pub data: Array2<u8>,
+    label: Array2<u32>,
+    kind: Array2<u8>,
+}
+
+impl Reference {
+    pub fn new(data: ArrayView2<u8>) -> Self {
+        let data = data.to_owned();
+        let (label, kind) = img::label(&data);
+        Self { data, label, kind }
+    }
+}
+
+/// Similarity of `data` to reference, up to `PIECE` points for each piece
+pub fn score(metric: Metric, data: &Array2<u8>, reference: &Reference) -> i32 {
+    let ref_map = &reference.data;
+    match metric {
+        Metric::Exact => {
+            PIECE
+                * data
+                    .iter()
+                    .zip(ref_map)
+                    .map(|(a, b)| (*a != 0 && a == b) as i32)
+                    .sum::<i32>()
+        }
+        Metric::Iou => {
+            let &[h, w] = data.shape() else {
+                unreachable!()
+            };
+            let ref_label = &reference.label;
+            let mut total = 0.0;
+            for ((y, x), &v) in data.indexed_iter() {
+                if v == 0 {
+                    continue;
+                }
+                let Some(cells) = piece::cells(v, (y, x), (h, w)) else {
+                    continue;
+                };
+                // most common reference label among the cells
+                let overlap = cells
+                    .iter()
+                    .map(|c| ref_label[*c])
+                    .filter(|id| *id != 0)
+                    .map(|id| cells.iter().filter(|c| ref_label[**c] == id).count())
+                    .max()
+                    .unwrap_or(0) as f64;
+                total += overlap / (8.0 - overlap);
+            }
+            (total * PIECE as f64).round() as i32
+        }
+        Metric::Type => {
+            let (_, kind) = img::label(data);
+            kind.iter()
+                .zip(&reference.kind)
+                .map(|(a, b)| (*a != 0 && piece::kind(*a) == piece::kind(*b)) as i32)
+                .sum()
+        }
+        Metric::Shift => {
+            let &[h, w] = data.shape() else {
+                unreachable!()
+            };
+            let mut total = 0;
+            for ((y, x), &v) in data.indexed_iter() {
+                if v == 0 {
+                    continue;
+                }
+                if ref_map[(y, x)] == v {
+                    total += PIECE;
+                    continue;
+                }
+                let mut best = 0;
+                for (dy, dx) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                    let (Some(y), Some(x)) = (y.checked_add_signed(dy), x.checked_add_signed(dx))
+                    else {
+                        continue;
+                    };
+                    if y < h && x < w && ref_map[(y, x)] == v {
+                        best = max(best, PIECE / 2);
+                    }
+                }
+                total += best;
+            }
+            total
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    #[test]
+    fn test_moved() {
+        let ref_map = array![
+            [2, 0, 0, 0, 0], //
+            [0, 0, 0, 0, 0],
+        ];
+        let reference = Reference::new(ref_map.view());
+        let data = array![
+            [0, 2, 0, 0, 0], //
+            [0, 0, 0, 0, 0],
+        ];
+        assert_eq!(score(Metric::Exact, &data, &reference), 0);
+        assert_eq!(score(Metric::Iou, &data, &reference), 2);
+        assert_eq!(score(Metric::Type, &data, &reference), 3);
+        assert_eq!(score(Metric::Shift, &data, &reference), 2);
+        assert_eq!(score(Metric::Iou, &ref_map, &reference), PIECE);
+    }
+}
@@ -0,0 +1,241 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use ndarray::Array2;
+
+use crate::{batch::frame_path, identity, img, npz, solve};
+
+/// One solved frame of a numbered sequence
+pub struct Frame {
+    pub index: usize,
+    pub output: PathBuf,
+    pub input: solve::Input,
+    pub data: Array2<u8>,
+    /// Persistent piece id for each covered cell
+    pub id: Array2<u32>,
+}
+
+/// Which neighbours a frame is re-solved against
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Next frame only, carrying layout back from the end
+    Backward,
+    /// Pieces both neighbours agree on, then the rest of next and previous frame
+    Both,
+}
+
+/// Read solved frames `from..=to`, stopping at the first one that is missing
+pub fn load(dir: &Path, from: usize, to: Option<usize>) -> Result<Vec<Frame>> {
+    let mut frames = vec![];
+    for index in from..=to.unwrap_or(usize::MAX) {
+        let (input, output) = frame_path(dir, index);
+        if !input.exists() || !output.exists() {
+            break;
+        }
+        let input = npz::read_input(&input)?;
+        let npz::Reference { data, id, .. } = npz::read_ref(&output, Some(input.map.shape()))?;
+        let id = match id {
+            Some(id) => id,
+            None => identity::assign(frames.last().map(|f: &Frame| &f.id), &data),
+        };
+        frames.push(Frame {
+            index,
+            output,
+            input,
+            data,
+            id,
+        });
+    }
+    Ok(frames)
+}
+
+/// Pieces of `a` and `b` that are not at the same anchor in the other
+pub fn churn(a: &Array2<u8>, b: &Array2<u8>) -> usize {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a != b) as usize * ((*a != 0) as usize + (*b != 0) as usize))
+        .sum()
+}
+
+/// Pieces of `data` that are in neither neighbour
+pub fn flicker(prev: &Array2<u8>, data: &Array2<u8>, next: &Array2<u8>) -> usize {
+    data.iter()
+        .zip(prev)
+        .zip(next)
+        .filter(|((v, p), n)| **v != 0 && v != p && v != n)
+        .count()
+}
+
+/// Churn against both neighbours, pieces that last one frame count twice
+fn cost(prev: Option<&Array2<u8>>, data: &Array2<u8>, next: Option<&Array2<u8>>) -> usize {
+    let mut cost = 0;
+    if let Some(prev) = prev {
+        cost += churn(prev, data);
+    }
+    if let Some(next) = next {
+        cost += churn(data, next);
+    }
+    if let (Some(prev), Some(next)) = (prev, next) {
+        cost += flicker(prev, data, next);
+    }
+    cost
+}
+
+/// Map cells a solved frame left uncovered
+fn unfilled(f: &Frame) -> Array2<bool> {
+    &f.input.map & &img::lay(&f.data).mapv(|v| !v)
+}
+
+/// Reference layout for re-solving a frame between `prev` and `next`
+pub fn between(
+    prev: Option<&Array2<u8>>,
+    next: Option<&Array2<u8>>,
+    direction: Direction,
+) -> Option<Array2<u8>> {
+    match (direction, prev, next) {
+        (Direction::Both, Some(prev), Some(next)) => {
+            let mut agreed = prev.clone();
+            agreed.zip_mut_with(next, |a, b| {
+                if a != b {
+                    *a = 0;
+                }
+            });
+            Some(img::overlay(&img::overlay(&agreed, next), prev))
+        }
+        (Direction::Both, prev, next) => next.or(prev).cloned(),
+        (Direction::Backward, _, next) => next.cloned(),
+    }
+}
+
+/// Re-solve frames from the last one, keeping a new layout if it fills as many pieces
+/// with less churn. `save` is called for each frame with a new layout or new piece ids.
+pub fn pass(
+    frames: &mut [Frame],
+    direction: Direction,
+    opts: &solve::Options,
+    mut save: impl FnMut(&Frame) -> Result<()>,
+) -> Result<usize> {
+    let mut changed = vec![false; frames.len()];
+    for i in (0..frames.len()).rev() {
+        let prev = (i > 0).then(|| &frames[i - 1].data);
+        let next = frames.get(i + 1).map(|f| &f.data);
+        let Some(ref_map) = between(prev, next, direction) else {
+            continue;
+        };
+        // cells the reference neighbours left empty
+        let empty = match (direction, i.checked_sub(1), frames.get(i + 1)) {
+            (Direction::Both, Some(p), Some(n)) => Some(&unfilled(&frames[p]) & &unfilled(n)),
+            (Direction::Both, p, n) => n.or(p.map(|p| &frames[p])).map(unfilled),
+            (Direction::Backward, _, n) => n.map(unfilled),
+        };
+        let f = &frames[i];
+        log!("smooth: frame #{:04}", f.index);
+        let data = solve::frame(&f.input, Some(ref_map), empty.as_ref(), opts);
+
+        let pieces = |d: &Array2<u8>| d.iter().filter(|v| **v != 0).count();
+        let (old, new) = (cost(prev, &f.data, next), cost(prev, &data, next));
+        log!("smooth: frame #{:04} churn {} -> {}", f.index, old, new);
+        if pieces(&data) < pieces(&f.data) || new >= old {
+            continue;
+        }
+        frames[i].data = data;
+        changed[i] = true;
+    }
+
+    // carry piece ids forward through the new layouts
+    let mut relabel = false;
+    for i in 0..frames.len() {
+        if changed[i] || relabel {
+            let prev_id = if i > 0 {
+                frames[i - 1].id.clone()
+            } else {
+                frames[i].id.clone()
+            };
+            let id = identity::assign(Some(&prev_id), &frames[i].data);
+            relabel = id != frames[i].id;
+            frames[i].id = id;
+            if changed[i] || relabel {
+                save(&frames[i])?;
+            }
+        }
+    }
+    Ok(changed.iter().filter(|c| **c).count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    #[test]
+    fn test_churn() {
+        let prev = array![
+            [1, 0, 1, 0, 0], //
+            [0, 0, 0, 0, 0],
+        ];
+        let data = array![
+            [1, 0, 0, 1, 0], //
+            [0, 0, 0, 0, 0],
+        ];
+        let next = array![
+            [1, 0, 1, 0, 0], //
+            [0, 0, 0, 0, 0],
+        ];
+        assert_eq!(churn(&prev, &data), 2);
+        assert_eq!(flicker(&prev, &data, &next), 1);
+        // the O piece both agree on wins over the one that flickers
+        let ref_map = between(Some(&prev), Some(&next), Direction::Both).unwrap();
+        assert_eq!(ref_map, prev);
+        assert_eq!(cost(Some(&prev), &ref_map, Some(&next)), 0);
+    }
+
+    #[test]
+    fn test_pass() {
+        let frame = |index, data: Array2<u8>| Frame {
+            index,
+            output: PathBuf::new(),
+            input: solve::Input {
+                map: Array2::from_elem((2, 4), true),
+                gray: None,
+                weight: None,
+                lock: None,
+                empty: None,
+            },
+            id: img::label(&data).0,
+            data,
+        };
+        let square = array![
+            [1, 0, 1, 0], //
+            [0, 0, 0, 0],
+        ];
+        // middle frame flips to two bars and back
+        let bars = array![
+            [2, 0, 0, 0], //
+            [2, 0, 0, 0],
+        ];
+        let mut frames = vec![
+            frame(0, square.clone()),
+            frame(1, bars),
+            frame(2, square.clone()),
+        ];
+        let opts = solve::Options {
+            motion: solve::Motion::Off,
+            max_shift: 0,
+            max_jump: 0.0,
+            cut_threshold: 0.0,
+            ..Default::default()
+        };
+        let mut saved = vec![];
+        let changed = pass(&mut frames, Direction::Both, &opts, |f| {
+            saved.push(f.index);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(changed, 1);
+        assert_eq!(frames[1].data, square);
+        // last frame keeps its ids, so only the new layout is written
+        assert_eq!(saved, vec![1]);
+        assert_eq!(frames[1].id, frames[0].id);
+    }
+}
@@ -0,0 +1,1215 @@
+use std::{
+    cmp::max,
+    collections::VecDeque,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use ndarray::prelude::*;
+use rayon::prelude::{IntoParallelRefMutIterator, ParallelIterator};
+
+use crate::{check, cleanup, dither, ga, img, motion, piece, remainder, similarity, split, track};
+
+#[derive(clap::Args, Debug, Clone)]
+#[command(next_help_heading = "Solver")]
+pub struct Options {
+    /// Shift reference layout to follow moving map
+    #[arg(long, global = true, value_enum, default_value_t = Motion::Segment)]
+    pub motion: Motion,
+    /// Largest shift in cells to search for
+    #[arg(long, global = true, default_value_t = 4)]
+    pub max_shift: isize,
+    /// Largest distance in cells to match a segment to the nearest previous one
+    #[arg(long, global = true, default_value_t = 12.0)]
+    pub max_jump: f64,
+    /// Similarity to reference layout used to rank solutions
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub similarity: similarity::Metric,
+    /// Fitness for each point of similarity to reference layout, filling a piece is worth 16.
+    /// 0 only ranks finished solutions by similarity
+    #[arg(long, global = true, default_value_t = 0)]
+    pub stability: i32,
+    /// Most changed cells to re-solve locally around kept reference pieces, 0 to always
+    /// solve from scratch
+    #[arg(long, global = true, default_value_t = 32)]
+    pub local_limit: usize,
+    /// Treat frame as a scene cut, solving it without reference, if its map overlaps
+    /// the reference layout less than this (cell IoU), 0 to never cut
+    #[arg(long, global = true, default_value_t = 0.2)]
+    pub cut_threshold: f64,
+    /// Most map cells to change bridging tiny islands, clearing spurs, filling pinholes and
+    /// clearing isolated cells before solving, 0 to solve the map as is
+    #[arg(long, global = true, default_value_t = 0)]
+    pub cleanup: usize,
+    /// Ignore `map` and place pieces anywhere, their density following `gray`
+    #[arg(long, global = true)]
+    pub dither: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            motion: Motion::Segment,
+            max_shift: 4,
+            max_jump: 12.0,
+            similarity: Default::default(),
+            stability: 0,
+            local_limit: 32,
+            cut_threshold: 0.2,
+            cleanup: 0,
+            dither: false,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Motion {
+    Off,
+    /// One shift for whole frame
+    Global,
+    /// Global shift, then refine for each matched previous segment
+    Segment,
+}
+
+/// Generations to grow a solution for at most
+const GENERATIONS: usize = 100000;
+
+/// Generations to re-solve a changed region for at most, per cell of the local limit
+const LOCAL_GENERATIONS: usize = 200;
+
+/// Seeds to try re-solving a changed region with
+const LOCAL_SEEDS: u64 = 3;
+
+/// One frame to solve
+pub struct Input {
+    pub map: Array2<bool>,
+    /// Source brightness
+    pub gray: Option<Array2<u8>>,
+    /// Importance of covering each cell, in any scale
+    pub weight: Option<Array2<f64>>,
+    /// Pieces pinned in place, anchor coded. They may cover cells outside `map`
+    pub lock: Option<Array2<u8>>,
+    /// Cells that must stay empty
+    pub empty: Option<Array2<bool>>,
+}
+
+/// Parts solved in an earlier run of the same frame
+#[derive(Default)]
+pub struct Cache {
+    parts: Vec<CachedPart>,
+    /// Parts the last run took from the cache
+    pub reused: usize,
+}
+
+struct CachedPart {
+    pos: (usize, usize),
+    map: Array2<bool>,
+    ref_map: Option<Array2<u8>>,
+    data: Array2<u8>,
+}
+
+/// Fill each segment of the map, keeping close to `ref_map` (layout of previous frame).
+/// `prev_unfilled` are the previous map cells it left uncovered.
+pub fn frame(
+    input: &Input,
+    ref_map: Option<Array2<u8>>,
+    prev_unfilled: Option<&Array2<bool>>,
+    opts: &Options,
+) -> Array2<u8> {
+    frame_cached(input, ref_map, prev_unfilled, opts, &mut Cache::default())
+}
+
+/// Same as `frame`, but parts whose reference is the same as in `cache` are not solved
+/// again. `cache` is replaced with the parts of this run.
+pub fn frame_cached(
+    input: &Input,
+    ref_map: Option<Array2<u8>>,
+    prev_unfilled: Option<&Array2<bool>>,
+    opts: &Options,
+    cache: &mut Cache,
+) -> Array2<u8> {
+    let gray = input.gray.as_ref();
+    let allowed = match &input.empty {
+        Some(empty) => empty.mapv(|v| !v),
+        None => Array2::from_elem(input.map.raw_dim(), true),
+    };
+    let locked = input.lock.as_ref().map(|lock| {
+        let locked = img::transfer(allowed.view(), lock.view());
+        let dropped = lock.iter().filter(|v| **v != 0).count() - piece_count(&locked);
+        if dropped > 0 {
+            log!("lock: {} pieces overlap or cover empty cells", dropped);
+        }
+        locked
+    });
+    let pinned = locked.as_ref().map(img::lay);
+    // locked pieces are placed up front, leaving only free cells to fill
+    let mut composite: Array2<u8> = locked
+        .clone()
+        .unwrap_or_else(|| Array2::zeros(input.map.raw_dim()));
+    let mut free = allowed.clone();
+    if let Some(pinned) = &pinned {
+        free = &free & &pinned.mapv(|v| !v);
+    }
+    let stability = ga::Stability {
+        metric: opts.similarity,
+        weight: opts.stability,
+    };
+    if opts.dither {
+        let target = match gray {
+            Some(gray) => gray.mapv(|v| v as f64 / 255.0),
+            None => {
+                log!("dither: no gray, following map");
+                input.map.mapv(|v| v as u8 as f64)
+            }
+        };
+        let ref_map = ref_map.map(|m| img::transfer(free.view(), m.view()));
+        composite += &dither::dither(&free, &target, ref_map, locked.as_ref(), stability);
+        cache.parts.clear();
+        cache.reused = 0;
+        return composite;
+    }
+    let mut map = &input.map & &free;
+    if opts.cleanup > 0 {
+        let changes;
+        (map, changes) = cleanup::clean(&map, &free, opts.cleanup);
+        if !changes.is_empty() {
+            let list: Vec<_> = changes.iter().map(|c| c.to_string()).collect();
+            log!("cleanup: {} cells {}", changes.len(), list.join(" "));
+        }
+    }
+    let map = &map;
+    let weight = input.weight.as_ref().and_then(|w| cell_weight(w, map));
+
+    let (ref_map, shift) = match ref_map {
+        Some(m) if opts.motion != Motion::Off => {
+            let d = compensate(map.view(), m.view(), (0, 0), opts.max_shift);
+            (Some(motion::shift(m.view(), d)), d)
+        }
+        m => (m, (0, 0)),
+    };
+    let ref_map = ref_map.filter(|m| {
+        let overlap = track::overlap(map, &img::lay(m));
+        if overlap < opts.cut_threshold {
+            log!("scene cut: overlap {:.2}", overlap);
+        }
+        overlap >= opts.cut_threshold
+    });
+    // cells that were in the previous map but left empty, following the reference
+    let prev_empty = prev_unfilled
+        .filter(|_| ref_map.is_some())
+        .map(|m| motion::shift_cells(m, shift));
+
+    let mut solved = vec![];
+    let mut reused = 0;
+    let segments: Vec<_> = img::segment(map)
+        .into_iter()
+        .filter(|seg| seg.map_size >= 4)
+        .collect();
+    let refs = ref_map.as_ref().map(track::ref_segments);
+    let matches = refs.as_ref().map(|refs| {
+        let matches = track::correspond(&segments, refs, opts.max_jump);
+        let vanished = track::vanished(refs, &matches);
+        if !vanished.is_empty() {
+            log!("vanished: {} segments", vanished.len());
+        }
+        matches
+    });
+
+    for (i, seg) in segments.iter().enumerate() {
+        let &[h, w] = seg.map.shape() else {
+            unreachable!()
+        };
+        let bbox = s![seg.y..(seg.y + h), seg.x..(seg.x + w)];
+        // pieces of matched previous segments only, and the cells they left empty
+        let matched = refs.as_ref().zip(matches.as_ref()).map(|(refs, matches)| {
+            let m = &matches[i];
+            if m.link != track::Link::Same {
+                log!("segment ({}, {}): {:?}", seg.y, seg.x, m.link);
+            }
+            let mut mask = Array2::from_elem(map.raw_dim(), false);
+            mask.slice_mut(bbox).assign(&seg.map);
+            let (cy, cx) = track::centroid(&mask);
+            let mut out = Array2::zeros(map.raw_dim());
+            let mut empty = prev_empty
+                .as_ref()
+                .map(|_| Array2::from_elem(map.raw_dim(), false));
+            for &j in &m.source {
+                let r = &refs[j];
+                let center = if m.nearest {
+                    (
+                        (cy - r.centroid.0).round() as isize,
+                        (cx - r.centroid.1).round() as isize,
+                    )
+                } else {
+                    (0, 0)
+                };
+                let d = match opts.motion {
+                    Motion::Segment => {
+                        compensate(mask.view(), r.data.view(), center, opts.max_shift)
+                    }
+                    _ => center,
+                };
+                out.zip_mut_with(&motion::shift(r.data.view(), d), |o, d| {
+                    if *o == 0 {
+                        *o = *d;
+                    }
+                });
+                if let (Some(empty), Some(prev_empty)) = (&mut empty, &prev_empty) {
+                    let moved = motion::shift_cells(&attached(prev_empty, &r.mask), d);
+                    *empty |= &moved;
+                }
+            }
+            (out, empty)
+        });
+        let (ref_map, seg_empty) = match matched {
+            Some((out, empty)) => (Some(out), empty),
+            None => (None, None),
+        };
+        let hint = remainder::Hint {
+            gray: gray.map(|m| m.slice(bbox)),
+            prev_empty: seg_empty.as_ref().map(|m| m.slice(bbox)),
+            weight: weight.as_ref().map(|m| m.slice(bbox)),
+        };
+        let trimmed = remainder::trim_remainder(seg, hint);
+        let parts = split::split(&trimmed);
+        if parts.len() > 1 {
+            log!("split into {} parts", parts.len());
+        }
+        for part in &parts {
+            let (y, x) = (seg.y + part.y, seg.x + part.x);
+            let &[h, w] = part.map.shape() else {
+                unreachable!()
+            };
+            let mut part_map = Array2::from_elem(map.raw_dim(), false);
+            part_map
+                .slice_mut(s![y..(y + h), x..(x + w)])
+                .assign(&part.map);
+            // hard mode can fall back to the untrimmed segment only if it was not split
+            let full_map = if parts.len() == 1 {
+                let mut full_map = part_map.clone();
+                full_map.slice_mut(bbox).assign(&seg.map);
+                full_map
+            } else {
+                part_map.clone()
+            };
+            // locked pieces next to the part count as filled while solving it, so the part
+            // is solved in a window that holds them whole
+            let locked = locked
+                .as_ref()
+                .map(|m| touching(m, &part_map))
+                .filter(|m| piece_count(m) > 0);
+            let (wy, wx) = window(&full_map, locked.as_ref());
+            let (y, x) = (wy.start, wx.start);
+            let win = s![wy, wx];
+            let part_map = part_map.slice(win).to_owned();
+            let full_map = full_map.slice(win).to_owned();
+            let locked = locked.map(|m| m.slice(win).to_owned());
+            let ref_map = ref_map.as_ref().map(|m| m.slice(win));
+            let weight = weight.as_ref().map(|m| m.slice(win));
+            let cached = cache.parts.iter().find(|c| {
+                c.pos == (y, x)
+                    && c.map == part_map
+                    && c.ref_map.as_ref().map(|m| m.view()) == ref_map
+            });
+            let data = if let Some(c) = cached {
+                log!("part ({}, {}): same reference, reusing", y, x);
+                reused += 1;
+                c.data.clone()
+            } else {
+                ref_map
+                    .and_then(|r| {
+                        reuse(
+                            &part_map,
+                            r,
+                            locked.as_ref(),
+                            weight,
+                            opts.local_limit,
+                            stability,
+                        )
+                    })
+                    .unwrap_or_else(|| {
+                        solve(
+                            &part_map,
+                            &full_map,
+                            ref_map,
+                            locked.as_ref(),
+                            weight,
+                            stability,
+                        )
+                    })
+            };
+
+            composite.slice_mut(win).zip_mut_with(&data, |comp, diff| {
+                *comp += diff;
+            });
+            solved.push(CachedPart {
+                pos: (y, x),
+                map: part_map,
+                ref_map: ref_map.map(|m| m.to_owned()),
+                data,
+            });
+        }
+    }
+    if !cache.parts.is_empty() {
+        log!("cache: reused {} of {} parts", reused, solved.len());
+    }
+    cache.parts = solved;
+    cache.reused = reused;
+    composite
+}
+
+/// Keep reference pieces that still fit `map` and re-solve only the cells they leave
+/// uncovered, freeing nearby pieces until those can be filled. `locked` pieces are kept
+/// too and left out of the result. `None` if more than `limit` cells changed or the changed
+/// region cannot be filled this way.
+fn reuse(
+    map: &Array2<bool>,
+    ref_map: ArrayView2<u8>,
+    locked: Option<&Array2<u8>>,
+    weight: Option<ArrayView2<i32>>,
+    limit: usize,
+    stability: ga::Stability,
+) -> Option<Array2<u8>> {
+    let kept = img::transfer(map.view(), ref_map);
+    let covered = img::lay(&kept);
+    let changed = map
+        .iter()
+        .zip(&covered)
+        .filter(|(m, c)| **m && !**c)
+        .count();
+    if limit == 0 || changed > limit {
+        return None;
+    }
+    if changed == 0 {
+        log!("reuse: unchanged");
+        return Some(kept);
+    }
+    let &[h, w] = map.shape() else { unreachable!() };
+    let mut near = map & &covered.mapv(|v| !v);
+    'radius: for radius in 1..=3 {
+        near = dilate(&near);
+        // reference pieces away from the change stay
+        let mut fixed = kept.clone();
+        for ((y, x), v) in kept.indexed_iter() {
+            if *v == 0 {
+                continue;
+            }
+            let cells = piece::cells(*v, (y, x), (h, w)).unwrap();
+            if cells.iter().any(|c| near[*c]) {
+                fixed[(y, x)] = 0;
+            }
+        }
+        let free = map & &img::lay(&fixed).mapv(|v| !v);
+        let regions = img::segment(&free);
+        if regions
+            .iter()
+            .any(|r| r.map_size % 4 != 0 || check::check(&r.map).is_err())
+        {
+            continue;
+        }
+        let mut out = fixed;
+        for r in &regions {
+            let &[rh, rw] = r.map.shape() else {
+                unreachable!()
+            };
+            let mut region = Array2::from_elem(map.raw_dim(), false);
+            region
+                .slice_mut(s![r.y..(r.y + rh), r.x..(r.x + rw)])
+                .assign(&r.map);
+            let locked = locked
+                .map(|m| touching(m, &region))
+                .filter(|m| piece_count(m) > 0);
+            let (wy, wx) = window(&region, locked.as_ref());
+            let win = s![wy, wx];
+            let Some(data) = solve_local(
+                &region.slice(win).to_owned(),
+                ref_map.slice(win),
+                locked.map(|m| m.slice(win).to_owned()).as_ref(),
+                weight.map(|m| m.slice_move(win)),
+                stability,
+                limit * LOCAL_GENERATIONS,
+            ) else {
+                continue 'radius;
+            };
+            out.slice_mut(win).zip_mut_with(&data, |o, d| *o += d);
+        }
+        log!("reuse: {} changed cells, radius {}", changed, radius);
+        return Some(out);
+    }
+    None
+}
+
+/// Cells of `empty` connected to `covered` through other `empty` cells
+fn attached(empty: &Array2<bool>, covered: &Array2<bool>) -> Array2<bool> {
+    let mut out = Array2::from_elem(empty.raw_dim(), false);
+    let mut stack: Vec<_> = covered
+        .indexed_iter()
+        .filter(|(_, v)| **v)
+        .map(|(pos, _)| pos)
+        .collect();
+    while let Some((y, x)) = stack.pop() {
+        for nb in [
+            (y.wrapping_sub(1), x),
+            (y + 1, x),
+            (y, x.wrapping_sub(1)),
+            (y, x + 1),
+        ] {
+            if empty.get(nb) == Some(&true) && !out[nb] {
+                out[nb] = true;
+                stack.push(nb);
+            }
+        }
+    }
+    out
+}
+
+/// Pieces of `data` with a cell within one step (including diagonal) of `mask`
+fn touching(data: &Array2<u8>, mask: &Array2<bool>) -> Array2<u8> {
+    let near = dilate(mask);
+    let mut out = Array2::zeros(data.raw_dim());
+    for (pos, &v) in data.indexed_iter().filter(|(_, v)| **v != 0) {
+        let Some(cells) = piece::cells(v, pos, data.dim()) else {
+            continue;
+        };
+        if cells.iter().any(|c| near[*c]) {
+            out[pos] = v;
+        }
+    }
+    out
+}
+
+/// Smallest window `(rows, columns)` holding `mask` and all cells of `pieces`
+fn window(mask: &Array2<bool>, pieces: Option<&Array2<u8>>) -> (Range<usize>, Range<usize>) {
+    let cover = match pieces {
+        Some(pieces) => mask | &img::lay(pieces),
+        None => mask.clone(),
+    };
+    let (mut y0, mut x0, mut y1, mut x1) = (usize::MAX, usize::MAX, 0, 0);
+    for ((y, x), _) in cover.indexed_iter().filter(|(_, v)| **v) {
+        (y0, x0, y1, x1) = (y0.min(y), x0.min(x), y1.max(y + 1), x1.max(x + 1));
+    }
+    (y0.min(y1)..y1, x0.min(x1)..x1)
+}
+
+/// Cells within one step (including diagonal) of `mask`
+fn dilate(mask: &Array2<bool>) -> Array2<bool> {
+    let &[h, w] = mask.shape() else {
+        unreachable!()
+    };
+    let mut out = mask.clone();
+    for ((y, x), _) in mask.indexed_iter().filter(|(_, v)| **v) {
+        for ny in y.saturating_sub(1)..(y + 2).min(h) {
+            for nx in x.saturating_sub(1)..(x + 2).min(w) {
+                out[(ny, nx)] = true;
+            }
+        }
+    }
+    out
+}
+
+/// Shift of reference layout that best matches `map`
+fn compensate(
+    map: ArrayView2<bool>,
+    ref_map: ArrayView2<u8>,
+    center: (isize, isize),
+    radius: isize,
+) -> (isize, isize) {
+    let d = motion::estimate(map, ref_map, center, radius);
+    if d != (0, 0) {
+        log!("motion: {:?}", d);
+    }
+    d
+}
+
+/// Fitness for covering each cell, 4 at the mean weight of `map` cells and at least 1, so
+/// that covering a cell always pays. `None` if the weights are all zero.
+fn cell_weight(weight: &Array2<f64>, map: &Array2<bool>) -> Option<Array2<i32>> {
+    let (sum, count) = weight
+        .iter()
+        .zip(map)
+        .filter(|(_, m)| **m)
+        .fold((0.0, 0), |(s, n), (w, _)| (s + w.max(0.0), n + 1));
+    if sum <= 0.0 {
+        return None;
+    }
+    let mean = sum / count as f64;
+    Some(weight.mapv(|w| ((4.0 * w.max(0.0) / mean).round() as i32).max(1)))
+}
+
+fn piece_count(data: &Array2<u8>) -> usize {
+    data.iter().filter(|v| **v != 0).count()
+}
+
+/// Fill `map`, or fall back to partially filling `full_map` if it cannot be fully filled,
+/// covering the cells of higher `weight` first. `locked` pieces are kept in every
+/// candidate and left out of the result.
+fn solve(
+    map: &Array2<bool>,
+    full_map: &Array2<bool>,
+    ref_map: Option<ArrayView2<u8>>,
+    locked: Option<&Array2<u8>>,
+    weight: Option<ArrayView2<i32>>,
+    stability: ga::Stability,
+) -> Array2<u8> {
+    let pinned = locked.map_or(0, piece_count) as i32 * 4;
+    let goal = map.iter().map(|x| *x as i32).sum::<i32>() / 4 * 4 + pinned;
+    let mut candidate = vec![];
+    let mut last_ga = None;
+    let mut success = false;
+    let start = Instant::now();
+    // skip straight to hard mode if it cannot be fully filled
+    let seeds = match check::check(map) {
+        Ok(()) => 20,
+        Err(reason) => {
+            log!("untileable: {}", reason);
+            0
+        }
+    };
+    let mut chain = RefChain {
+        ref_map,
+        fit: fit_ref,
+        fallback: None,
+    };
+    for seed in 0..seeds {
+        // transfer ref
+        let ref_map = chain.get(map.view(), seed);
+
+        match grow(
+            map.view(),
+            ref_map.as_ref().map(|x| x.view()),
+            locked,
+            weight,
+            seed,
+            goal,
+            false,
+            stability,
+            GENERATIONS,
+        ) {
+            Ok(ga) => {
+                success = true;
+                candidate.push(ga.candidate[0].clone());
+                last_ga = Some(ga);
+                if candidate.len() >= 3 || ref_map.is_none() {
+                    break;
+                }
+            }
+            Err(ga) => {
+                chain.failed();
+                last_ga = Some(ga);
+            }
+        }
+    }
+    log!("elapsed: {:?}", start.elapsed());
+    if success {
+        let ga = last_ga.as_ref().unwrap();
+        log!("generation: {}", ga.generation);
+        for row in img::dump(&ga.cfg.map, &candidate[0].data) {
+            log!("|{}|", row);
+        }
+        log!("  {:?} = {}", candidate[0].raw_score, ga.candidate[0].score);
+    } else {
+        if let Some(ga) = &last_ga {
+            log!("{:?}", ga);
+            log!("Failed");
+            // add failed candidate for base line
+            candidate.push(ga.candidate[0].clone());
+        }
+
+        // try hard mode
+        for seed in 0..5 {
+            // transfer ref
+            let ref_map = chain.get(map.view(), seed);
+
+            let goal = full_map.iter().map(|x| *x as i32).sum::<i32>() / 4 * 4 + pinned;
+            match grow(
+                full_map.view(),
+                ref_map.as_ref().map(|x| x.view()),
+                locked,
+                weight,
+                seed,
+                goal,
+                true,
+                stability,
+                GENERATIONS,
+            ) {
+                Ok(ga) => {
+                    candidate.push(ga.candidate[0].clone());
+                    break;
+                }
+                Err(ga) => {
+                    candidate.push(ga.candidate[0].clone());
+                }
+            }
+        }
+    }
+
+    rank(&mut candidate, ref_map, stability.metric);
+    let mut data = (*candidate[0].data).clone();
+    if let Some(locked) = locked {
+        data.zip_mut_with(locked, |d, l| {
+            if *l != 0 {
+                *d = 0;
+            }
+        });
+    }
+    data
+}
+
+/// Sort candidates by coverage, then by similarity to `ref_map`
+fn rank(
+    candidate: &mut [ga::Candidate],
+    ref_map: Option<ArrayView2<u8>>,
+    metric: similarity::Metric,
+) {
+    let reference = ref_map.map(similarity::Reference::new);
+    candidate.par_iter_mut().for_each(|c| {
+        let similarity = (reference.as_ref())
+            .map(|r| similarity::score(metric, &c.data, r))
+            .unwrap_or(0);
+        c.score = if let img::EvalResult::Valid { .. } = c.raw_score {
+            c.coverage * 10 * similarity::PIECE / 4 + similarity
+        } else {
+            0
+        };
+    });
+    candidate.sort_by_key(|c| -c.score);
+}
+
+/// Fill all of a small changed region starting from `ref_map`, within `generations`.
+/// `locked` pieces count as filled and are left out of the result. `None` if no seed fills it.
+fn solve_local(
+    map: &Array2<bool>,
+    ref_map: ArrayView2<u8>,
+    locked: Option<&Array2<u8>>,
+    weight: Option<ArrayView2<i32>>,
+    stability: ga::Stability,
+    generations: usize,
+) -> Option<Array2<u8>> {
+    let pinned = locked.map_or(0, piece_count) * 4;
+    let goal = (map.iter().filter(|v| **v).count() + pinned) as i32;
+    let ref_map = img::transfer(map.view(), ref_map);
+    let mut candidate: Vec<_> = (0..LOCAL_SEEDS)
+        .filter_map(|seed| {
+            let ga = grow(
+                map.view(),
+                Some(ref_map.view()),
+                locked,
+                weight,
+                seed,
+                goal,
+                false,
+                stability,
+                generations,
+            )
+            .ok()?;
+            Some(ga.candidate[0].clone())
+        })
+        .collect();
+    if candidate.is_empty() {
+        return None;
+    }
+    rank(&mut candidate, Some(ref_map.view()), stability.metric);
+    let mut data = (*candidate[0].data).clone();
+    if let Some(locked) = locked {
+        data.zip_mut_with(locked, |d, l| {
+            if *l != 0 {
+                *d = 0;
+            }
+        });
+    }
+    Some(data)
+}
+
+/// Why a reference layout cannot be used as is
+#[derive(Debug)]
+pub enum RefError {
+    /// Trimming left more than one unfilled area in every seed, `partial` is the closest
+    Trim { seeds: u64, partial: Array2<u8> },
+    /// No reference piece fits the map
+    NoFit,
+}
+
+impl std::fmt::Display for RefError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefError::Trim { seeds, .. } => {
+                write!(f, "trim failed for {} seeds", seeds)
+            }
+            RefError::NoFit => write!(f, "no reference piece fits"),
+        }
+    }
+}
+
+impl std::error::Error for RefError {}
+
+/// Number of seeds to trim reference with
+const TRIM_SEEDS: u64 = 10;
+
+/// Fit reference layout to `map`, trimmed to leave a single unfilled area
+pub fn fit_ref(
+    map: ArrayView2<bool>,
+    ref_map: ArrayView2<u8>,
+    seed: u64,
+) -> Result<Array2<u8>, RefError> {
+    if piece_count(&img::transfer(map, ref_map)) == 0 {
+        return Err(RefError::NoFit);
+    }
+    let mut partial: Option<(i32, Array2<u8>)> = None;
+    for seed2 in 0..TRIM_SEEDS {
+        match trim(map, ref_map, seed + 1000 * seed2) {
+            Ok(out) => return Ok(out),
+            Err(out) => {
+                let fragment = match img::eval(&map.to_owned(), &out) {
+                    img::EvalResult::Valid { fragment, .. } => fragment,
+                    img::EvalResult::Invalid => i32::MAX,
+                };
+                if partial.as_ref().is_none_or(|(f, _)| fragment < *f) {
+                    partial = Some((fragment, out));
+                }
+            }
+        }
+    }
+    Err(RefError::Trim {
+        seeds: TRIM_SEEDS,
+        partial: partial.unwrap().1,
+    })
+}
+
+/// Fits a reference layout to a map, see `fit_ref`
+type Fit = fn(ArrayView2<bool>, ArrayView2<u8>, u64) -> Result<Array2<u8>, RefError>;
+
+/// Reference to seed each solve with. Steps down to the partial reference once fitting
+/// fails, then to none once solving with the partial one fails too.
+struct RefChain<'a> {
+    ref_map: Option<ArrayView2<'a, u8>>,
+    /// `fit_ref` but in tests
+    fit: Fit,
+    /// Set after fitting failed
+    fallback: Option<Option<Array2<u8>>>,
+}
+
+impl RefChain<'_> {
+    fn get(&mut self, map: ArrayView2<bool>, seed: u64) -> Option<Array2<u8>> {
+        if let Some(fallback) = &self.fallback {
+            return fallback.clone();
+        }
+        match (self.fit)(map, self.ref_map?, seed) {
+            Ok(out) => Some(out),
+            Err(e) => {
+                log!("ref: {}", e);
+                let fallback = match e {
+                    RefError::Trim { partial, .. } => {
+                        log!("ref: using partial reference");
+                        Some(partial)
+                    }
+                    RefError::NoFit => None,
+                };
+                self.fallback = Some(fallback.clone());
+                fallback
+            }
+        }
+    }
+
+    /// Solving with current reference failed
+    fn failed(&mut self) {
+        if let Some(Some(_)) = self.fallback {
+            log!("ref: partial reference failed, solving without reference");
+            self.fallback = Some(None);
+        }
+    }
+}
+
+/// Remove reference pieces until the rest of `map` is one unfilled area,
+/// or the closest attempt if that fails
+fn trim(
+    map: ArrayView2<bool>,
+    ref_map: ArrayView2<u8>,
+    seed: u64,
+) -> Result<Array2<u8>, Array2<u8>> {
+    let new_ref = img::transfer(map.view(), ref_map);
+    let mut ga = ga::GA::new(
+        ga::Config {
+            map: map.to_owned(),
+            score: ga::score_trim,
+            ..Default::default()
+        },
+        seed,
+        Some(new_ref),
+    );
+    ga.cfg.score_phase = ga.cfg.map_size as i32;
+    let success = loop {
+        if ga.generation.is_multiple_of(100) {
+            ga.cfg.score_phase -= 4;
+            ga.rescore();
+        }
+        ga.step();
+        if matches!(ga.candidate[0].raw_score,
+            img::EvalResult::Valid { fragment, .. } if fragment <= 1)
+        {
+            break true;
+        }
+        if ga.generation >= 100000 {
+            break false;
+        }
+    };
+    if !success {
+        return Err((*ga.candidate[0].data).clone());
+    }
+    log!("trim: {}", success);
+    for row in img::dump(&ga.cfg.map, &ga.candidate[0].data) {
+        log!("|{}|", row);
+    }
+    Ok((*ga.candidate[0].data).clone())
+}
+
+#[allow(clippy::too_many_arguments, clippy::result_large_err)]
+fn grow(
+    map: ArrayView2<bool>,
+    ref_map: Option<ArrayView2<u8>>,
+    locked: Option<&Array2<u8>>,
+    weight: Option<ArrayView2<i32>>,
+    seed: u64,
+    goal: i32,
+    try_hard: bool,
+    stability: ga::Stability,
+    generations: usize,
+) -> Result<ga::GA, ga::GA> {
+    let map = match locked {
+        Some(locked) => &map | &img::lay(locked),
+        None => map.to_owned(),
+    };
+    let mut ga = ga::GA::new(
+        ga::Config {
+            map,
+            ref_map: ref_map.map(similarity::Reference::new),
+            locked: locked.cloned(),
+            weight: weight.map(|w| w.to_owned()),
+            stability,
+            ..Default::default()
+        },
+        seed,
+        None,
+    );
+    if let Some(locked) = locked {
+        // growing from each group of locked pieces
+        if let img::EvalResult::Valid { chunk, .. } = img::eval(&ga.cfg.map, locked) {
+            ga.cfg.score_chunk = max(1, chunk);
+            ga.rescore();
+        }
+    }
+    if let Some(ref_map) = ref_map {
+        ga.add_candidate(ref_map.to_owned());
+        if ga.candidate[0].score < 0 {
+            ga.cfg.score_chunk = match ga.candidate[0].raw_score {
+                img::EvalResult::Valid { chunk, .. } => max(1, chunk),
+                _ => 1,
+            };
+            ga.rescore();
+        }
+    }
+    let mut last_score: VecDeque<_> = [-1, -2, -3].into();
+    let mut status_timer = Instant::now();
+    loop {
+        ga.step();
+        if matches!(ga.candidate[0].raw_score,
+            img::EvalResult::Valid { filled, .. } if filled >= goal)
+        {
+            return Ok(ga);
+        }
+        if ga.generation >= generations {
+            return Err(ga);
+        }
+        let score = ga.candidate[0].score;
+        if ga.generation.is_multiple_of(1000) {
+            if last_score.iter().all(|v| *v == score) {
+                log!("seed: {} stuck @ gen: {}", seed, ga.generation);
+                if try_hard {
+                    if ga.cfg.score_phase == 1 {
+                        return Err(ga);
+                    }
+                    ga.cfg.score_phase = 1;
+                    ga.rescore();
+                    continue;
+                } else {
+                    return Err(ga);
+                }
+            }
+            last_score.pop_front();
+            last_score.push_back(score);
+        }
+
+        // show progress
+        if ga.generation.is_multiple_of(1000) && status_timer.elapsed() > Duration::from_secs(3) {
+            log!(
+                "generation: {}, score: {}",
+                ga.generation,
+                ga.candidate[0].score
+            );
+            for row in img::dump(&ga.cfg.map, &ga.candidate[0].data) {
+                log!("|{}|", row);
+            }
+            log!("   {:?}", ga.candidate[0].raw_score);
+            status_timer = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_options() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            opts: Options,
+        }
+        let cli = <Cli as clap::Parser>::parse_from(["tetris"]);
+        assert_eq!(
+            format!("{:?}", cli.opts),
+            format!("{:?}", Options::default())
+        );
+    }
+
+    #[test]
+    fn test_attached() {
+        let covered = array![[1, 1, 0, 0, 0, 1]].mapv(|v| v != 0);
+        let empty = array![[0, 0, 1, 1, 0, 0]].mapv(|v| v != 0);
+        // the hole next to the left segment goes with it, not with the right one
+        assert_eq!(attached(&empty, &covered), empty);
+        let right = array![[0, 0, 0, 0, 0, 1]].mapv(|v| v != 0);
+        assert!(!attached(&empty, &right).iter().any(|v| *v));
+    }
+
+    #[test]
+    fn test_reuse() {
+        let map = Array2::from_elem((2, 8), true);
+        let ref_map = array![
+            [1, 0, 1, 0, 1, 0, 1, 0], //
+            [0, 0, 0, 0, 0, 0, 0, 0],
+        ];
+        let out = reuse(&map, ref_map.view(), None, None, 32, Default::default()).unwrap();
+        assert_eq!(out, ref_map);
+
+        // right end is new, only the piece next to it is freed
+        let ref_map = array![
+            [1, 0, 1, 0, 1, 0, 0, 0], //
+            [0, 0, 0, 0, 0, 0, 0, 0],
+        ];
+        let out = reuse(&map, ref_map.view(), None, None, 32, Default::default()).unwrap();
+        assert_eq!(out.slice(s![.., ..4]), ref_map.slice(s![.., ..4]));
+        assert!(img::lay(&out).iter().all(|v| *v));
+        assert!(reuse(&map, ref_map.view(), None, None, 2, Default::default()).is_none());
+    }
+
+    #[test]
+    fn test_lock() {
+        let mut lock = Array2::zeros((2, 8));
+        lock[(1, 0)] = 2;
+        let mut empty = Array2::from_elem((2, 8), false);
+        empty.slice_mut(s![.., 6..]).fill(true);
+        let input = Input {
+            map: Array2::from_elem((2, 8), true),
+            gray: None,
+            weight: None,
+            lock: Some(lock),
+            empty: Some(empty),
+        };
+        let opts = Options {
+            motion: Motion::Off,
+            max_shift: 0,
+            max_jump: 0.0,
+            stability: 1,
+            local_limit: 0,
+            cut_threshold: 0.0,
+            ..Default::default()
+        };
+        let out = frame(&input, None, None, &opts);
+        assert_eq!(out[(1, 0)], 2);
+        let covered = img::lay(&out);
+        assert_eq!(
+            covered.slice(s![.., ..6]).iter().filter(|v| **v).count(),
+            12
+        );
+        assert!(!covered.slice(s![.., 6..]).iter().any(|v| *v));
+    }
+
+    #[test]
+    fn test_locked_window() {
+        // a vertical I locked in the left column, reaching past the top two rows
+        let mut lock = Array2::zeros((4, 5));
+        lock[(0, 0)] = 3;
+        let mut mask = Array2::from_elem((4, 5), false);
+        mask.slice_mut(s![..2, 1..]).fill(true);
+        assert_eq!(touching(&lock, &mask), lock);
+        assert_eq!(window(&mask, None), (0..2, 1..5));
+        assert_eq!(window(&mask, Some(&lock)), (0..4, 0..5));
+        mask.slice_mut(s![.., 1]).fill(false);
+        assert_eq!(piece_count(&touching(&lock, &mask)), 0);
+
+        // the changed bottom row is re-solved next to the locked piece
+        let mut map = Array2::from_elem((4, 5), true);
+        map.column_mut(0).fill(false);
+        let ref_map = array![
+            [0, 2, 0, 0, 0], //
+            [0, 2, 0, 0, 0],
+            [0, 2, 0, 0, 0],
+            [0, 0, 0, 0, 0],
+        ];
+        let out = reuse(
+            &map,
+            ref_map.view(),
+            Some(&lock),
+            None,
+            8,
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(out.slice(s![..3, ..]), ref_map.slice(s![..3, ..]));
+        assert_eq!(out[(0, 0)], 0);
+        assert_eq!(img::lay(&out), map);
+
+        let input = Input {
+            map: Array2::from_elem((4, 5), true),
+            gray: None,
+            weight: None,
+            lock: Some(lock),
+            empty: None,
+        };
+        let opts = Options {
+            motion: Motion::Off,
+            max_shift: 0,
+            max_jump: 0.0,
+            local_limit: 8,
+            cut_threshold: 0.0,
+            ..Default::default()
+        };
+        let out = frame(&input, Some(ref_map), None, &opts);
+        assert_eq!(out[(0, 0)], 3);
+        assert!(img::lay(&out).iter().all(|v| *v));
+    }
+
+    #[test]
+    fn test_fit_ref() {
+        let map = Array2::from_elem((2, 4), true);
+        let ref_map = array![
+            [0, 0, 0, 1], //
+            [0, 0, 0, 0],
+        ];
+        assert!(matches!(
+            fit_ref(map.view(), ref_map.view(), 0),
+            Err(RefError::NoFit)
+        ));
+        let ref_map = array![
+            [1, 0, 0, 0], //
+            [0, 0, 0, 0],
+        ];
+        assert!(fit_ref(map.view(), ref_map.view(), 0).is_ok());
+
+        // trimming fails: the partial reference is used until solving with it fails,
+        // then no reference
+        let mut chain = RefChain {
+            ref_map: Some(ref_map.view()),
+            fit: |map, ref_map, _| {
+                Err(RefError::Trim {
+                    seeds: TRIM_SEEDS,
+                    partial: img::transfer(map, ref_map),
+                })
+            },
+            fallback: None,
+        };
+        assert_eq!(chain.get(map.view(), 0), Some(ref_map.clone()));
+        assert_eq!(chain.get(map.view(), 1), Some(ref_map.clone()));
+        chain.failed();
+        assert_eq!(chain.get(map.view(), 2), None);
+        chain.failed();
+        assert_eq!(chain.get(map.view(), 3), None);
+        let mut chain = RefChain {
+            fit: |_, _, _| Err(RefError::NoFit),
+            fallback: None,
+            ..chain
+        };
+        assert_eq!(chain.get(map.view(), 0), None);
+    }
+
+    #[test]
+    fn test_weight() {
+        let map = Array2::from_elem((1, 5), true);
+        let weight = array![[2.0, 2.0, 2.0, 2.0, 0.0]];
+        assert_eq!(cell_weight(&weight, &map).unwrap(), array![[5, 5, 5, 5, 1]]);
+        assert!(cell_weight(&Array2::zeros((1, 5)), &map).is_none());
+
+        // the light end is left uncovered, whichever end it is
+        let opts = Options {
+            motion: Motion::Off,
+            max_shift: 0,
+            max_jump: 0.0,
+            stability: 1,
+            local_limit: 0,
+            cut_threshold: 0.0,
+            ..Default::default()
+        };
+        for (weight, expected) in [
+            (weight, array![[2, 0, 0, 0, 0]]),
+            (array![[0.0, 1.0, 1.0, 1.0, 1.0]], array![[0, 2, 0, 0, 0]]),
+        ] {
+            let input = Input {
+                map: map.clone(),
+                gray: None,
+                weight: Some(weight),
+                lock: None,
+                empty: None,
+            };
+            assert_eq!(frame(&input, None, None, &opts), expected);
+        }
+
+        // zero weight cells are still covered when the whole map can be
+        let map = Array2::from_elem((2, 8), true);
+        let weight = Array2::from_shape_fn((2, 8), |(_, x)| (x < 4) as u8 as f64);
+        let input = Input {
+            map,
+            gray: None,
+            weight: Some(weight),
+            lock: None,
+            empty: None,
+        };
+        assert!(img::lay(&frame(&input, None, None, &opts))
+            .iter()
+            .all(|v| *v));
+    }
+
+    #[test]
+    fn test_cache() {
+        let input = Input {
+            map: Array2::from_elem((1, 4), true),
+            gray: None,
+            weight: None,
+            lock: None,
+            empty: None,
+        };
+        let opts = Options {
+            motion: Motion::Off,
+            max_shift: 0,
+            max_jump: 0.0,
+            stability: 1,
+            local_limit: 0,
+            cut_threshold: 0.0,
+            ..Default::default()
+        };
+        let mut cache = Cache::default();
+        frame_cached(&input, None, None, &opts, &mut cache);
+        assert_eq!(cache.parts.len(), 1);
+        // same reference, so the cached part is used as is
+        let marker = Array2::zeros((1, 4));
+        cache.parts[0].data = marker.clone();
+        assert_eq!(frame_cached(&input, None, None, &opts, &mut cache), marker);
+        assert_eq!(cache.reused, 1);
+        let ref_map = Some(array![[2, 0, 0, 0]]);
+        assert_ne!(
+            frame_cached(&input, ref_map, None, &opts, &mut cache),
+            marker
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use crate::check;
+use crate::img;
+use crate::img::Segment;
+use ndarray::{s, Array2};
+use std::cmp::{max, min};
+
+type Edge = ((usize, usize), (usize, usize));
+
+/// Split `map` at narrow necks (1 or 2 cells wide) into parts that can be tiled on their own.
+/// Parts' `x` and `y` are relative to `map`.
+pub fn split(map: &Array2<bool>) -> Vec<Segment> {
+    let size = map.iter().filter(|v| **v).count();
+    let whole = Segment {
+        map: map.clone(),
+        map_size: size,
+        x: 0,
+        y: 0,
+    };
+    let mut done = vec![];
+    let mut todo = vec![whole];
+    while let Some(seg) = todo.pop() {
+        match cut(&seg.map) {
+            Some(parts) => {
+                for mut part in parts {
+                    part.x += seg.x;
+                    part.y += seg.y;
+                    todo.push(part);
+                }
+            }
+            None => done.push(seg),
+        }
+    }
+    done.sort_by_key(|seg| (seg.y, seg.x));
+    done
+}
+
+/// Find the most balanced cut where every resulting part passes the infeasibility check.
+/// The far side of the cut is split into its connected components.
+fn cut(map: &Array2<bool>) -> Option<Vec<Segment>> {
+    let size = map.iter().filter(|v| **v).count();
+    if size < 8 {
+        return None;
+    }
+
+    // keep only cuts that disconnect the map into sizes divisible by 4
+    let mut cuts: Vec<(usize, Array2<bool>)> = candidates(map)
+        .into_iter()
+        .filter_map(|edges| {
+            let reach = reach(map, edges[0].0, &edges);
+            if edges.iter().any(|(_, b)| reach[*b]) {
+                return None;
+            }
+            let side = reach.iter().filter(|v| **v).count();
+            (side % 4 == 0).then_some((side, reach))
+        })
+        .collect();
+    cuts.sort_by_key(|(side, _)| -(min(*side, size - *side) as isize));
+
+    for (_, reach) in cuts {
+        let a = crop(&(map & &reach));
+        let rest = img::segment(&(map & &reach.mapv(|v| !v)));
+        let mut parts = vec![a];
+        parts.extend(rest);
+        if parts.iter().all(|p| check::check(&p.map).is_ok()) {
+            return Some(parts);
+        }
+    }
+    None
+}
+
+/// Single edges and pairs of parallel edges that could disconnect `map`.
+/// An edge with both cells beside it filled on either side has a detour around it, so it is skipped.
+fn candidates(map: &Array2<bool>) -> Vec<Vec<Edge>> {
+    let &[h, w] = map.shape() else { unreachable!() };
+    let filled = |y: Option<usize>, x: Option<usize>| match (y, x) {
+        (Some(y), Some(x)) => y < h && x < w && map[(y, x)],
+        _ => false,
+    };
+    // both cells of column/row `i` next to the edge are filled
+    let row = |i: Option<usize>, x: usize| filled(i, Some(x)) && filled(i, Some(x + 1));
+    let col = |y: usize, i: Option<usize>| filled(Some(y), i) && filled(Some(y + 1), i);
+
+    let mut cuts = vec![];
+    for ((y, x), &v) in map.indexed_iter() {
+        if !v {
+            continue;
+        }
+        // horizontal neighbour, and the parallel one below
+        if x < w - 1 && map[(y, x + 1)] {
+            let edge = ((y, x), (y, x + 1));
+            if !row(y.checked_sub(1), x) && !row(Some(y + 1), x) {
+                cuts.push(vec![edge]);
+            }
+            if y < h - 1 && row(Some(y + 1), x) && !row(y.checked_sub(1), x) && !row(Some(y + 2), x)
+            {
+                cuts.push(vec![edge, ((y + 1, x), (y + 1, x + 1))]);
+            }
+        }
+        // vertical neighbour, and the parallel one to the right
+        if y < h - 1 && map[(y + 1, x)] {
+            let edge = ((y, x), (y + 1, x));
+            if !col(y, x.checked_sub(1)) && !col(y, Some(x + 1)) {
+                cuts.push(vec![edge]);
+            }
+            if x < w - 1 && col(y, Some(x + 1)) && !col(y, x.checked_sub(1)) && !col(y, Some(x + 2))
+            {
+                cuts.push(vec![edge, ((y, x + 1), (y + 1, x + 1))]);
+            }
+        }
+    }
+    cuts
+}
+
+/// Cells reachable from `start` without crossing `cut`
+fn reach(map: &Array2<bool>, start: (usize, usize), cut: &[Edge]) -> Array2<bool> {
+    let &[h, w] = map.shape() else { unreachable!() };
+    let blocked = |a: (usize, usize), b: (usize, usize)| {
+        cut.iter()
+            .any(|&(c, d)| (a, b) == (c, d) || (a, b) == (d, c))
+    };
+    let mut seen = Array2::from_elem(map.raw_dim(), false);
+    let mut stack = vec![start];
+    seen[start] = true;
+    while let Some((y, x)) = stack.pop() {
+        let mut next = vec![];
+        if y > 0 {
+            next.push((y - 1, x));
+        }
+        if y < h - 1 {
+            next.push((y + 1, x));
+        }
+        if x > 0 {
+            next.push((y, x - 1));
+        }
+        if x < w - 1 {
+            next.push((y, x + 1));
+        }
+        for n in next {
+            if map[n] && !seen[n] && !blocked((y, x), n) {
+                seen[n] = true;
+                stack.push(n);
+            }
+        }
+    }
+    seen
+}
+
+fn crop(map: &Array2<bool>) -> Segment {
+    let &[h, w] = map.shape() else { unreachable!() };
+    let (mut y_min, mut y_max, mut x_min, mut x_max) = (h, 0, w, 0);
+    for ((y, x), _) in map.indexed_iter().filter(|(_, v)| **v) {
+        y_max = max(y_max, y);
+        y_min = min(y_min, y);
+        x_max = max(x_max, x);
+        x_min = min(x_min, x);
+    }
+    let map = map
+        .slice(s![y_min..(y_max + 1), x_min..(x_max + 1)])
+        .to_owned();
+    Segment {
+        map_size: map.iter().filter(|v| **v).count(),
+        map,
+        x: x_min,
+        y: y_min,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    #[test]
+    fn test_neck() {
+        let map = array![
+            [1, 1, 0, 0, 0, 0, 0, 0], //
+            [1, 1, 1, 1, 1, 1, 1, 1],
+            [0, 0, 0, 0, 0, 0, 1, 1],
+        ]
+        .mapv(|x| x != 0);
+        let parts = split(&map);
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|p| p.map_size == 4));
+        let total: usize = parts.iter().map(|p| p.map_size).sum();
+        assert_eq!(total, 12);
+        assert!(parts.iter().all(|p| img::segment(&p.map).len() == 1));
+    }
+    #[test]
+    fn test_no_cut() {
+        let map = Array2::from_elem((4, 4), true);
+        let parts = split(&map);
+        assert_eq!(parts.len(), 1);
+    }
+    #[test]
+    fn test_candidates() {
+        let map = Array2::from_elem((8, 8), true);
+        assert!(candidates(&map).is_empty());
+
+        let map = array![
+            [1, 1, 0, 0, 0, 0, 0, 0], //
+            [1, 1, 1, 1, 1, 1, 1, 1],
+            [0, 0, 0, 0, 0, 0, 1, 1],
+        ]
+        .mapv(|x| x != 0);
+        let cuts = candidates(&map);
+        assert!(cuts.contains(&vec![((1, 1), (1, 2))]));
+        assert!(cuts.contains(&vec![((1, 5), (1, 6))]));
+        // the vertical edges inside the 2x2 ends have a detour
+        assert!(!cuts.contains(&vec![((0, 0), (1, 0))]));
+    }
+}
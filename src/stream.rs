@@ -0,0 +1,168 @@
+use std::io::{Read, Write};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use ndarray::Array2;
+
+use crate::{img, preprocess, render, solve};
+
+/// Pixel format of each input frame, one byte per cell
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Nonzero is filled
+    Mask,
+    /// 8-bit grayscale (ffmpeg -pix_fmt gray)
+    Gray,
+}
+
+/// What is written to stdout for each frame
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// Anchor-coded piece array, one byte per cell
+    Piece,
+    /// rgb24 image
+    Render,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct Config {
+    /// Input frame size in pixel, WIDTHxHEIGHT
+    #[arg(long, value_parser = preprocess::parse_size)]
+    pub size: (usize, usize),
+    #[arg(long, value_enum, default_value_t = Format::Gray)]
+    pub format: Format,
+    #[command(flatten)]
+    pub prep: preprocess::Options,
+    #[arg(long, value_enum, default_value_t = Output::Piece)]
+    pub output: Output,
+    #[command(flatten)]
+    pub style: render::Style,
+}
+
+/// Read the next frame into `buf`. Returns false at end of input.
+fn read_frame(input: &mut impl Read, buf: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).context("Cannot read input"),
+        }
+    }
+    if filled != 0 && filled != buf.len() {
+        bail!("truncated frame: {} of {} bytes", filled, buf.len());
+    }
+    Ok(filled != 0)
+}
+
+/// Convert raw bytes of one frame to solver input
+pub fn input(cfg: &Config, buf: &[u8], state: &mut preprocess::Hysteresis) -> solve::Input {
+    let (w, h) = cfg.size;
+    let raw = Array2::from_shape_vec((h, w), buf.to_vec()).unwrap();
+    let (map, gray) = match cfg.format {
+        Format::Mask => (
+            preprocess::frame(&raw.mapv(|v| v.min(1) * 255), &cfg.prep, state).0,
+            None,
+        ),
+        Format::Gray => {
+            let (map, gray) = preprocess::frame(&raw, &cfg.prep, state);
+            (map, Some(gray))
+        }
+    };
+    solve::Input {
+        map,
+        gray,
+        weight: None,
+        lock: None,
+        empty: None,
+    }
+}
+
+/// Solve frames read from `input` until it ends, each against the previous one,
+/// writing each result to `output` as soon as it is solved
+pub fn run(
+    cfg: &Config,
+    opts: &solve::Options,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<usize> {
+    let (w, h) = cfg.size;
+    if cfg.output == Output::Render {
+        let style = &cfg.style;
+        let (w, h) = cfg.prep.grid.unwrap_or(cfg.size);
+        log!("## Output option:");
+        log!(
+            "## ffmpeg -video_size {}x{} -pix_fmt rgb24 -f rawvideo -i - out.mp4",
+            w * style.scale,
+            h * style.scale
+        );
+    }
+    let mut buf = vec![0; w * h];
+    let mut prev = None;
+    let mut state = preprocess::Hysteresis::default();
+    let mut index = 0;
+    while read_frame(&mut input, &mut buf)? {
+        log!("frame #{:04}", index);
+        let frame = self::input(cfg, &buf, &mut state);
+        let unfilled = prev
+            .as_ref()
+            .map(|(map, data): &(Array2<bool>, _)| map & &img::lay(data).mapv(|v| !v));
+        let data = solve::frame(&frame, prev.take().map(|p| p.1), unfilled.as_ref(), opts);
+        let bytes = match cfg.output {
+            Output::Piece => data.iter().copied().collect(),
+            Output::Render => render::render(&frame.map, &data, &cfg.style),
+        };
+        output
+            .write_all(&bytes)
+            .and_then(|_| output.flush())
+            .context("Cannot write output")?;
+        prev = Some((frame.map, data));
+        index += 1;
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream() {
+        let cfg = Config {
+            size: (4, 1),
+            format: Format::Gray,
+            prep: Default::default(),
+            output: Output::Piece,
+            style: render::Style {
+                scale: 1,
+                stroke: 0,
+                unfilled: false,
+            },
+        };
+        let opts = solve::Options {
+            motion: solve::Motion::Off,
+            stability: 1,
+            ..Default::default()
+        };
+        let frames = [200u8, 255, 128, 200, 255, 255, 255, 255];
+        let mut out = vec![];
+        let n = run(&cfg, &opts, &frames[..], &mut out).unwrap();
+        assert_eq!(n, 2);
+        assert_eq!(out, vec![2, 0, 0, 0, 2, 0, 0, 0]);
+        // a partial frame at the end is an error
+        assert!(run(&cfg, &opts, &frames[..6], &mut vec![]).is_err());
+        // 8x1 pixels downsampled to 4 cells
+        let cfg = Config {
+            size: (8, 1),
+            prep: preprocess::Options {
+                grid: Some((4, 1)),
+                ..Default::default()
+            },
+            ..cfg
+        };
+        let mut out = vec![];
+        assert_eq!(run(&cfg, &opts, &frames[..], &mut out).unwrap(), 1);
+        assert_eq!(out, vec![2, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::img::{self, Segment};
+use crate::piece;
+use ndarray::{s, Array2};
+
+/// Reference pieces that cover one contiguous area of previous frame
+pub struct RefSegment {
+    /// Anchor coded pieces, in frame coordinates
+    pub data: Array2<u8>,
+    /// Covered cells, in frame coordinates
+    pub mask: Array2<bool>,
+    pub centroid: (f64, f64),
+}
+
+/// How a current segment relates to previous frame
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Link {
+    New,
+    Same,
+    /// Part of a previous segment that is split
+    Split,
+    /// Previous segments joined together
+    Merged,
+}
+
+/// Previous segments matched to one current segment
+pub struct Match {
+    /// Indices to reference segments
+    pub source: Vec<usize>,
+    pub link: Link,
+    /// Matched to the nearest segment without overlap
+    pub nearest: bool,
+}
+
+/// Group reference pieces by the contiguous area they cover
+pub fn ref_segments(ref_map: &Array2<u8>) -> Vec<RefSegment> {
+    let &[h, w] = ref_map.shape() else {
+        unreachable!()
+    };
+    let covered = img::lay(ref_map);
+    let mut id: Array2<usize> = Array2::zeros(ref_map.raw_dim());
+    let mut out = vec![];
+    for seg in img::segment(&covered) {
+        let &[sh, sw] = seg.map.shape() else {
+            unreachable!()
+        };
+        let mut mask = Array2::from_elem(ref_map.raw_dim(), false);
+        mask.slice_mut(s![seg.y..(seg.y + sh), seg.x..(seg.x + sw)])
+            .assign(&seg.map);
+        out.push(RefSegment {
+            data: Array2::zeros(ref_map.raw_dim()),
+            centroid: centroid(&mask),
+            mask,
+        });
+        id.slice_mut(s![seg.y..(seg.y + sh), seg.x..(seg.x + sw)])
+            .zip_mut_with(&seg.map, |i, v| {
+                if *v {
+                    *i = out.len();
+                }
+            });
+    }
+    for ((y, x), &v) in ref_map.indexed_iter() {
+        if v == 0 {
+            continue;
+        }
+        if let Some([first, ..]) = piece::cells(v, (y, x), (h, w)) {
+            out[id[first] - 1].data[(y, x)] = v;
+        }
+    }
+    out
+}
+
+/// Match each segment (in frame coordinates) to overlapping previous segments,
+/// or the nearest one within `max_dist` cells, preferring ones that nothing overlaps
+pub fn correspond(segments: &[Segment], refs: &[RefSegment], max_dist: f64) -> Vec<Match> {
+    let mut out: Vec<Match> = segments
+        .iter()
+        .map(|seg| {
+            let &[h, w] = seg.map.shape() else {
+                unreachable!()
+            };
+            let source: Vec<_> = (0..refs.len())
+                .filter(|&i| {
+                    let mask = refs[i]
+                        .mask
+                        .slice(s![seg.y..(seg.y + h), seg.x..(seg.x + w)]);
+                    mask.iter().zip(&seg.map).any(|(a, b)| *a && *b)
+                })
+                .collect();
+            Match {
+                nearest: source.is_empty(),
+                source,
+                link: Link::Same,
+            }
+        })
+        .collect();
+
+    let mut used = vec![0; refs.len()];
+    for m in &out {
+        for &i in &m.source {
+            used[i] += 1;
+        }
+    }
+    for (seg, m) in segments.iter().zip(&mut out) {
+        if !m.nearest {
+            continue;
+        }
+        let (cy, cx) = centroid(&seg.map);
+        let (cy, cx) = (cy + seg.y as f64, cx + seg.x as f64);
+        let nearest = (0..refs.len())
+            .map(|i| (i, (refs[i].centroid.0 - cy).hypot(refs[i].centroid.1 - cx)))
+            .filter(|(_, d)| *d <= max_dist)
+            .min_by(|a, b| {
+                (used[a.0] > 0, a.1)
+                    .partial_cmp(&(used[b.0] > 0, b.1))
+                    .unwrap()
+            });
+        if let Some((i, _)) = nearest {
+            m.source.push(i);
+            used[i] += 1;
+        }
+    }
+    for m in &mut out {
+        m.link = if m.source.is_empty() {
+            Link::New
+        } else if m.source.len() > 1 {
+            Link::Merged
+        } else if used[m.source[0]] > 1 {
+            Link::Split
+        } else {
+            Link::Same
+        };
+    }
+    out
+}
+
+/// Reference segments that no current segment is matched to
+pub fn vanished(refs: &[RefSegment], matches: &[Match]) -> Vec<usize> {
+    (0..refs.len())
+        .filter(|i| !matches.iter().any(|m| m.source.contains(i)))
+        .collect()
+}
+
+/// Cell IoU of two masks, 1 if both are empty
+pub fn overlap(a: &Array2<bool>, b: &Array2<bool>) -> f64 {
+    let both = a.iter().zip(b).filter(|(a, b)| **a && **b).count();
+    let either = a.iter().zip(b).filter(|(a, b)| **a || **b).count();
+    if either == 0 {
+        return 1.0;
+    }
+    both as f64 / either as f64
+}
+
+pub fn centroid(mask: &Array2<bool>) -> (f64, f64) {
+    let (mut sy, mut sx, mut n) = (0, 0, 0);
+    for ((y, x), _) in mask.indexed_iter().filter(|(_, v)| **v) {
+        sy += y;
+        sx += x;
+        n += 1;
+    }
+    let n = n.max(1) as f64;
+    (sy as f64 / n, sx as f64 / n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    #[test]
+    fn test_correspond() {
+        // two O pieces, and an I piece far away
+        let ref_map = array![
+            [1, 0, 0, 1, 0, 0, 0, 0, 0, 0], //
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0, 0, 2, 0, 0, 0],
+        ];
+        let refs = ref_segments(&ref_map);
+        assert_eq!(refs.len(), 3);
+        let map = array![
+            [1, 1, 1, 1, 1, 0, 0, 0, 0, 0], //
+            [1, 1, 1, 1, 1, 0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        ]
+        .mapv(|x| x != 0);
+        let segments = img::segment(&map);
+        let matches = correspond(&segments, &refs, 4.0);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].link, Link::Merged);
+        assert_eq!(vanished(&refs, &matches), vec![2]);
+        assert_eq!(overlap(&map, &img::lay(&ref_map)), 8.0 / 14.0);
+    }
+}
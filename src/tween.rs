@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use ndarray::Array2;
+
+use crate::{identity, npz, piece};
+
+/// What happens to a piece between two frames
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Event {
+    Persist = 0,
+    Move = 1,
+    /// Turned, possibly moved too
+    Rotate = 2,
+    Appear = 3,
+    Disappear = 4,
+}
+
+/// Keyframes of one piece from previous to current frame
+#[derive(Debug, Clone)]
+pub struct Key {
+    pub id: u32,
+    pub event: Event,
+    /// Piece type in current frame, or previous frame if it disappears
+    pub piece: u8,
+    /// Center of the piece, in cells. Same as `to` if it appears
+    pub from: (f64, f64),
+    /// Same as `from` if it disappears
+    pub to: (f64, f64),
+    /// Quarter turns clockwise around the center, -1 to 2
+    pub turn: i8,
+}
+
+/// Piece type and center for each piece id
+fn pieces(data: &Array2<u8>, id: &Array2<u32>) -> BTreeMap<u32, (u8, (f64, f64))> {
+    let &[h, w] = data.shape() else {
+        unreachable!()
+    };
+    let mut out = BTreeMap::new();
+    for (pos, &v) in data.indexed_iter() {
+        let Some(cells) = piece::cells(v, pos, (h, w)).filter(|_| v != 0) else {
+            continue;
+        };
+        let (sy, sx) = cells
+            .iter()
+            .fold((0, 0), |(sy, sx), (y, x)| (sy + y, sx + x));
+        out.insert(id[cells[0]], (v, (sy as f64 / 4.0, sx as f64 / 4.0)));
+    }
+    out
+}
+
+/// Match pieces of two consecutive layouts by their persistent id. A piece that changes
+/// kind under the same id disappears and appears again.
+pub fn keys(
+    (prev, prev_id): (&Array2<u8>, &Array2<u32>),
+    (data, id): (&Array2<u8>, &Array2<u32>),
+) -> Vec<Key> {
+    let before = pieces(prev, prev_id);
+    let after = pieces(data, id);
+    let mut out = vec![];
+    let appear = |id, v, to| Key {
+        id,
+        event: Event::Appear,
+        piece: v,
+        from: to,
+        to,
+        turn: 0,
+    };
+    let disappear = |id, v, from| Key {
+        id,
+        event: Event::Disappear,
+        piece: v,
+        from,
+        to: from,
+        turn: 0,
+    };
+    for (&id, &(v, to)) in &after {
+        let key = match before.get(&id) {
+            None => appear(id, v, to),
+            Some(&(u, from)) => {
+                let Some(turn) = piece::turns(u, v) else {
+                    out.push(disappear(id, u, from));
+                    out.push(appear(id, v, to));
+                    continue;
+                };
+                let turn = match turn {
+                    3 => -1,
+                    t => t as i8,
+                };
+                let event = if turn != 0 {
+                    Event::Rotate
+                } else if from != to {
+                    Event::Move
+                } else {
+                    Event::Persist
+                };
+                Key {
+                    id,
+                    event,
+                    piece: v,
+                    from,
+                    to,
+                    turn,
+                }
+            }
+        };
+        out.push(key);
+    }
+    for (&id, &(v, from)) in &before {
+        if !after.contains_key(&id) {
+            out.push(disappear(id, v, from));
+        }
+    }
+    out
+}
+
+/// Layout and piece ids of an output file, ids carried on from `prev_id` if it has none
+fn layout(path: &str, prev_id: Option<&Array2<u32>>) -> Result<(Array2<u8>, Array2<u32>)> {
+    let npz::Reference { data, id, .. } = npz::read_ref(path, None)?;
+    let id = match id {
+        Some(id) => id,
+        None => identity::assign(prev_id, &data),
+    };
+    Ok((data, id))
+}
+
+/// Write keyframes between output files `prev` and `next` to `output`, by default next to
+/// `next` with a _tween.npz suffix
+pub fn run(prev: &str, next: &str, output: Option<&str>) -> Result<()> {
+    let (prev_data, prev_id) = layout(prev, None)?;
+    let (data, id) = layout(next, Some(&prev_id))?;
+    let keys = keys((&prev_data, &prev_id), (&data, &id));
+    for event in [
+        Event::Persist,
+        Event::Move,
+        Event::Rotate,
+        Event::Appear,
+        Event::Disappear,
+    ] {
+        let n = keys.iter().filter(|k| k.event == event).count();
+        log!("{:?}: {}", event, n);
+    }
+    let output = output.map(String::from).unwrap_or_else(|| {
+        let stem = next.strip_suffix(".npz").unwrap_or(next);
+        let stem = stem.strip_suffix("_out").unwrap_or(stem);
+        format!("{}_tween.npz", stem)
+    });
+    npz::write_keys(output, &keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity;
+    use ndarray::prelude::*;
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(piece::turns(2, 3), Some(1));
+        assert_eq!(piece::turns(1, 1), Some(0));
+        assert_eq!(piece::turns(4, 8), None);
+        assert_eq!(piece::turns(4, 7), Some(1));
+        assert_eq!(piece::turns(5, 4), Some(1));
+
+        let prev = array![
+            [1, 0, 2, 0, 0, 0, 0, 0], //
+            [0, 0, 0, 0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0, 0, 0, 0],
+        ];
+        let data = array![
+            [0, 0, 0, 3, 0, 0, 0, 0], //
+            [1, 0, 0, 0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0, 0, 0, 0],
+        ];
+        let prev_id = identity::assign(None, &prev);
+        let id = identity::assign(Some(&prev_id), &data);
+        let keys = keys((&prev, &prev_id), (&data, &id));
+        let events: Vec<_> = keys.iter().map(|k| k.event).collect();
+        assert_eq!(events, vec![Event::Move, Event::Rotate]);
+        assert_eq!(keys[0].to.0 - keys[0].from.0, 1.0);
+        assert_eq!(keys[1].turn, 1);
+
+        // O becoming an I under the same id is not a rotation
+        let prev = array![
+            [1, 0, 0, 0], //
+            [0, 0, 0, 0],
+            [0, 0, 0, 0],
+        ];
+        let data = array![
+            [0, 0, 0, 0], //
+            [2, 0, 0, 0],
+            [0, 0, 0, 0],
+        ];
+        let prev_id = identity::assign(None, &prev);
+        let id = identity::assign(Some(&prev_id), &data);
+        let changed = super::keys((&prev, &prev_id), (&data, &id));
+        let events: Vec<_> = changed.iter().map(|k| (k.id, k.event, k.piece)).collect();
+        assert_eq!(
+            events,
+            vec![(1, Event::Disappear, 1), (1, Event::Appear, 2)]
+        );
+    }
+}